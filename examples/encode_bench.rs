@@ -0,0 +1,90 @@
+// Compare encoding a large batch of search result entries through the lber
+// Tag tree against the codec's direct encoder.
+//
+//   cargo run --release --example encode_bench [entries]
+
+use bytes::BytesMut;
+use lber::structure::StructureTag;
+use ldap3_server::proto::*;
+use ldap3_server::LdapCodec;
+use std::time::{Duration, Instant};
+use tokio_util::codec::Encoder;
+
+fn entry(i: usize) -> LdapMsg {
+    LdapMsg::new(
+        2,
+        LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: format!("uid=user{},ou=people,dc=example,dc=com", i),
+            attributes: vec![
+                LdapPartialAttribute {
+                    atype: "objectClass".to_string(),
+                    vals: vec![
+                        b"top".to_vec(),
+                        b"person".to_vec(),
+                        b"organizationalPerson".to_vec(),
+                        b"inetOrgPerson".to_vec(),
+                    ],
+                },
+                LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec![format!("user{}", i).into_bytes()],
+                },
+                LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec![format!("Demo User {}", i).into_bytes()],
+                },
+                LdapPartialAttribute {
+                    atype: "mail".to_string(),
+                    vals: vec![format!("user{}@example.com", i).into_bytes()],
+                },
+                LdapPartialAttribute {
+                    atype: "memberOf".to_string(),
+                    vals: (0..20)
+                        .map(|g| format!("cn=group{},ou=groups,dc=example,dc=com", g).into_bytes())
+                        .collect(),
+                },
+                LdapPartialAttribute {
+                    atype: "jpegPhoto".to_string(),
+                    vals: vec![vec![0x5a; 2048]],
+                },
+            ],
+        }),
+    )
+}
+
+fn run<F: FnMut(LdapMsg, &mut BytesMut)>(batch: &[LdapMsg], mut f: F) -> (Duration, BytesMut) {
+    let msgs = batch.to_vec();
+    let mut buf = BytesMut::new();
+    let start = Instant::now();
+    for msg in msgs {
+        f(msg, &mut buf);
+    }
+    (start.elapsed(), buf)
+}
+
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(10_000);
+    let batch: Vec<_> = (0..count).map(entry).collect();
+
+    let (lber_time, lber_buf) = run(&batch, |msg, buf| {
+        lber::write::encode_into(buf, StructureTag::from(msg)).expect("failed to encode");
+    });
+
+    let mut codec = LdapCodec::default();
+    let (direct_time, direct_buf) = run(&batch, |msg, buf| {
+        codec.encode(msg, buf).expect("failed to encode");
+    });
+
+    assert_eq!(lber_buf, direct_buf);
+
+    println!("{} entries, {} bytes", count, direct_buf.len());
+    println!("lber tag tree: {:?}", lber_time);
+    println!("direct:        {:?}", direct_time);
+    println!(
+        "speedup:       {:.2}x",
+        lber_time.as_secs_f64() / direct_time.as_secs_f64()
+    );
+}
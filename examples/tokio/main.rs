@@ -2,7 +2,6 @@ use tokio::net::{TcpListener, TcpStream};
 // use tokio::stream::StreamExt;
 use futures::SinkExt;
 use futures::StreamExt;
-use std::convert::TryFrom;
 use std::net;
 use std::str::FromStr;
 use tokio_util::codec::{FramedRead, FramedWrite};
@@ -10,6 +9,10 @@ use tokio_util::codec::{FramedRead, FramedWrite};
 use ldap3_server::simple::*;
 use ldap3_server::LdapCodec;
 
+// Signs paged search cookies. A real server should load a random secret,
+// shared by every instance, rather than build one in.
+const PAGED_COOKIE_KEY: &[u8] = b"an example key, replace with 32 random bytes";
+
 pub struct LdapSession {
     dn: String,
 }
@@ -19,7 +22,7 @@ impl LdapSession {
         if sbr.dn == "cn=Directory Manager" && sbr.pw == "password" {
             self.dn = sbr.dn.to_string();
             sbr.gen_success()
-        } else if sbr.dn == "" && sbr.pw == "" {
+        } else if sbr.dn.is_empty() && sbr.pw.is_empty() {
             self.dn = "Anonymous".to_string();
             sbr.gen_success()
         } else {
@@ -27,36 +30,53 @@ impl LdapSession {
         }
     }
 
+    pub fn do_sasl_bind(&mut self, sbr: &SaslBindRequest) -> LdapMsg {
+        match &sbr.creds {
+            SaslCredentials::Plain {
+                authzid: None,
+                authcid,
+                password,
+            } if authcid == "cn=Directory Manager" && password == "password" => {
+                self.dn = authcid.to_string();
+                sbr.gen_success(None)
+            }
+            SaslCredentials::Plain { .. } => sbr.gen_invalid_cred(),
+            _ => sbr.gen_auth_method_not_supported(),
+        }
+    }
+
     pub fn do_search(&mut self, lsr: &SearchRequest) -> Vec<LdapMsg> {
-        vec![
-            lsr.gen_result_entry(LdapSearchResultEntry {
-                dn: "cn=hello,dc=example,dc=com".to_string(),
-                attributes: vec![
-                    LdapPartialAttribute {
-                        atype: "objectClass".to_string(),
-                        vals: vec!["cursed".to_string()],
-                    },
-                    LdapPartialAttribute {
-                        atype: "cn".to_string(),
-                        vals: vec!["hello".to_string()],
-                    },
-                ],
-            }),
-            lsr.gen_result_entry(LdapSearchResultEntry {
-                dn: "cn=world,dc=example,dc=com".to_string(),
-                attributes: vec![
-                    LdapPartialAttribute {
-                        atype: "objectClass".to_string(),
-                        vals: vec!["cursed".to_string()],
-                    },
-                    LdapPartialAttribute {
-                        atype: "cn".to_string(),
-                        vals: vec!["world".to_string()],
-                    },
-                ],
-            }),
-            lsr.gen_success(),
-        ]
+        lsr.gen_paged(
+            PAGED_COOKIE_KEY,
+            vec![
+                LdapSearchResultEntry {
+                    dn: "cn=hello,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![Vec::from("cursed")],
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![Vec::from("hello")],
+                        },
+                    ],
+                },
+                LdapSearchResultEntry {
+                    dn: "cn=world,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![Vec::from("cursed")],
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![Vec::from("world")],
+                        },
+                    ],
+                },
+            ],
+        )
     }
 
     pub fn do_whoami(&mut self, wr: &WhoamiRequest) -> LdapMsg {
@@ -67,18 +87,33 @@ impl LdapSession {
 async fn handle_client(socket: TcpStream, _paddr: net::SocketAddr) {
     // Configure the codec etc.
     let (r, w) = tokio::io::split(socket);
-    let mut reqs = FramedRead::new(r, LdapCodec);
-    let mut resp = FramedWrite::new(w, LdapCodec);
+    let mut reqs = FramedRead::new(r, LdapCodec::default());
+    let mut resp = FramedWrite::new(w, LdapCodec::default());
 
     let mut session = LdapSession {
         dn: "Anonymous".to_string(),
     };
 
+    let mut controls = SupportedControls::new();
+    controls.register(LdapPagedResults::OID);
+
     while let Some(msg) = reqs.next().await {
-        let server_op = match msg
-            .map_err(|_e| ())
-            .and_then(|msg| ServerOps::try_from(msg))
-        {
+        let server_op = match msg {
+            Ok(Ok(msg)) => ServerOps::try_from_with_controls(msg, &controls),
+            Ok(Err(malformed)) => {
+                // We know which request this was, so we can tell the client
+                // it was bad and carry on with the connection.
+                if let Some(rmsg) = malformed.gen_protocol_error() {
+                    if resp.send(rmsg).await.is_err() {
+                        return;
+                    }
+                }
+                continue;
+            }
+            Err(_) => Err(()),
+        };
+
+        let server_op = match server_op {
             Ok(v) => v,
             Err(_) => {
                 let _err = resp
@@ -94,21 +129,27 @@ async fn handle_client(socket: TcpStream, _paddr: net::SocketAddr) {
 
         let result = match server_op {
             ServerOps::SimpleBind(sbr) => vec![session.do_bind(&sbr)],
+            ServerOps::SaslBind(sbr) => vec![session.do_sasl_bind(&sbr)],
             ServerOps::Search(sr) => session.do_search(&sr),
             ServerOps::Unbind(_) => {
                 // No need to notify on unbind (per rfc4511)
                 return;
             }
             ServerOps::Whoami(wr) => vec![session.do_whoami(&wr)],
+            ServerOps::UnavailableCriticalExtension(rmsg) => vec![rmsg],
+            // Nothing to reply, and the request must not be carried out.
+            ServerOps::DroppedCriticalExtension(_) => vec![],
+            ServerOps::UnsupportedVersion(rmsg) => vec![rmsg],
+            ServerOps::InvalidSaslCredentials(rmsg) => vec![rmsg],
         };
 
         for rmsg in result.into_iter() {
-            if let Err(_) = resp.send(rmsg).await {
+            if resp.send(rmsg).await.is_err() {
                 return;
             }
         }
 
-        if let Err(_) = resp.flush().await {
+        if resp.flush().await.is_err() {
             return;
         }
     }
@@ -129,7 +170,7 @@ async fn acceptor(listener: Box<TcpListener>) {
 }
 
 #[tokio::main]
-async fn main() -> () {
+async fn main() {
     let addr = net::SocketAddr::from_str("127.0.0.1:12345").unwrap();
     let listener = Box::new(TcpListener::bind(&addr).await.unwrap());
 
@@ -0,0 +1,536 @@
+// Views of messages that borrow from the frame they were read from, rather
+// than copying every dn, type and value into an owned String or Vec. Only
+// search result entries are decoded this way, as they are what a server or
+// proxy relays in bulk - other ops are left encoded until they are converted
+// to an owned LdapMsg. That conversion decodes them just as LdapCodec does,
+// so they are not zero-copy.
+
+use crate::proto::{
+    ber_bool_to_bool, ber_integer_to_i64, check_count, LdapControl, LdapOp, LdapPartialAttribute,
+    LdapProtoError, LdapSearchResultEntry,
+};
+use crate::{ber_check_op_limits, ber_header, LdapCodecError, LdapLimits, LdapMsg};
+use lber::common::TagClass;
+use lber::parse::parse_tag;
+use lber::universal::Types;
+use lber::IResult;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapMsgRef<'a> {
+    pub msgid: i32,
+    pub op: LdapOpRef<'a>,
+    pub ctrl: Vec<LdapControlRef<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapOpRef<'a> {
+    SearchResultEntry(LdapSearchResultEntryRef<'a>),
+    /// Any other op, as its encoded bytes. These are only decoded when the
+    /// message is converted to an LdapMsg, and are copied as they are.
+    Other(&'a [u8]),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapControlRef<'a> {
+    pub oid: &'a str,
+    pub criticality: bool,
+    pub value: Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSearchResultEntryRef<'a> {
+    pub dn: &'a str,
+    pub attributes: Vec<LdapPartialAttributeRef<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapPartialAttributeRef<'a> {
+    pub atype: &'a str,
+    pub vals: Vec<&'a [u8]>,
+}
+
+/// A single BER element within a frame.
+struct Element<'a> {
+    class: TagClass,
+    constructed: bool,
+    id: u64,
+    /// The whole element, including its identifier and length.
+    raw: &'a [u8],
+    contents: &'a [u8],
+}
+
+impl<'a> Element<'a> {
+    fn split(buf: &'a [u8]) -> Result<(Self, &'a [u8]), LdapCodecError> {
+        let (hdr_len, len) = ber_header(buf)?.ok_or(LdapCodecError::Ber)?;
+        let end = hdr_len
+            .checked_add(len)
+            .filter(|end| *end <= buf.len())
+            .ok_or(LdapCodecError::Ber)?;
+        let (raw, rem) = buf.split_at(end);
+        let element = Element {
+            class: TagClass::from_u8(raw[0] >> 6).ok_or(LdapCodecError::Ber)?,
+            constructed: raw[0] & 0x20 != 0,
+            id: (raw[0] & 0x1f) as u64,
+            raw,
+            contents: &raw[hdr_len..],
+        };
+        Ok((element, rem))
+    }
+
+    fn universal(&self, ty: Types, constructed: bool) -> bool {
+        self.class == TagClass::Universal && self.id == ty as u64 && self.constructed == constructed
+    }
+
+    fn string(self) -> Option<&'a str> {
+        self.octets().and_then(|bv| std::str::from_utf8(bv).ok())
+    }
+
+    fn octets(self) -> Option<&'a [u8]> {
+        if self.universal(Types::OctetString, false) {
+            Some(self.contents)
+        } else {
+            None
+        }
+    }
+
+    fn sequence(self, ty: Types) -> Option<Elements<'a>> {
+        if self.universal(ty, true) {
+            Some(Elements(self.contents))
+        } else {
+            None
+        }
+    }
+}
+
+/// The elements within a constructed element.
+struct Elements<'a>(&'a [u8]);
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Result<Element<'a>, LdapCodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        match Element::split(self.0) {
+            Ok((element, rem)) => {
+                self.0 = rem;
+                Some(Ok(element))
+            }
+            Err(e) => {
+                self.0 = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// As proto's decode_field, but over borrowed elements.
+fn decode_field<'a, T, F>(
+    element: Option<Result<Element<'a>, LdapCodecError>>,
+    structure: &'static str,
+    field: &'static str,
+    f: F,
+) -> Result<T, LdapCodecError>
+where
+    F: FnOnce(Element<'a>) -> Option<T>,
+{
+    let element = element.ok_or(LdapProtoError::Missing { structure, field })??;
+    let (class, id) = (element.class, element.id);
+    f(element).ok_or(LdapCodecError::Proto(LdapProtoError::Invalid {
+        structure,
+        field,
+        class,
+        id,
+    }))
+}
+
+impl<'a> TryFrom<&'a [u8]> for LdapMsgRef<'a> {
+    type Error = LdapCodecError;
+
+    /// Decode a frame, as produced by `LdapFrameCodec`.
+    fn try_from(frame: &'a [u8]) -> Result<Self, Self::Error> {
+        let mut inner = match Element::split(frame)? {
+            (msg, []) => decode_field(Some(Ok(msg)), "LDAPMessage", "", |e| {
+                e.sequence(Types::Sequence)
+            })?,
+            _ => return Err(LdapCodecError::Ber),
+        };
+
+        let msgid = decode_field(inner.next(), "LDAPMessage", "messageID", |e| {
+            if e.universal(Types::Integer, false) {
+                ber_integer_to_i64(e.contents).map(|i| i as i32)
+            } else {
+                None
+            }
+        })?;
+
+        let op = inner.next().ok_or(LdapProtoError::Missing {
+            structure: "LDAPMessage",
+            field: "protocolOp",
+        })??;
+        let op = match (op.class, op.id, op.constructed) {
+            (TagClass::Application, 4, true) => {
+                LdapOpRef::SearchResultEntry(LdapSearchResultEntryRef::try_from(op)?)
+            }
+            _ => LdapOpRef::Other(op.raw),
+        };
+
+        let ctrl = match inner.next().transpose()? {
+            Some(e) if e.class == TagClass::Context && e.id == 0 && e.constructed => {
+                Elements(e.contents)
+                    .map(|c| LdapControlRef::try_from(c?))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => Vec::new(),
+        };
+
+        if let Some(e) = inner.next().transpose()? {
+            return Err(LdapCodecError::Proto(LdapProtoError::Unexpected {
+                structure: "LDAPMessage",
+                class: e.class,
+                id: e.id,
+            }));
+        }
+
+        Ok(LdapMsgRef { msgid, op, ctrl })
+    }
+}
+
+impl<'a> TryFrom<Element<'a>> for LdapControlRef<'a> {
+    type Error = LdapCodecError;
+
+    fn try_from(value: Element<'a>) -> Result<Self, Self::Error> {
+        let mut inner = decode_field(Some(Ok(value)), "Control", "", |e| {
+            e.sequence(Types::Sequence)
+        })?;
+
+        let oid = decode_field(inner.next(), "Control", "controlType", Element::string)?;
+
+        let mut criticality = false;
+        let mut seen_criticality = false;
+        let mut value = None;
+        for e in inner {
+            let e = e?;
+            if e.universal(Types::Boolean, false) && value.is_none() && !seen_criticality {
+                seen_criticality = true;
+                criticality = decode_field(Some(Ok(e)), "Control", "criticality", |e| {
+                    ber_bool_to_bool(e.contents)
+                })?;
+            } else if e.universal(Types::OctetString, false) && value.is_none() {
+                value = Some(e.contents);
+            } else {
+                return Err(LdapCodecError::Proto(LdapProtoError::Unexpected {
+                    structure: "Control",
+                    class: e.class,
+                    id: e.id,
+                }));
+            }
+        }
+
+        Ok(LdapControlRef {
+            oid,
+            criticality,
+            value,
+        })
+    }
+}
+
+impl<'a> TryFrom<Element<'a>> for LdapSearchResultEntryRef<'a> {
+    type Error = LdapCodecError;
+
+    fn try_from(value: Element<'a>) -> Result<Self, Self::Error> {
+        let mut inner = Elements(value.contents);
+
+        let dn = decode_field(
+            inner.next(),
+            "SearchResultEntry",
+            "objectName",
+            Element::string,
+        )?;
+
+        let attributes = decode_field(inner.next(), "SearchResultEntry", "attributes", |e| {
+            e.sequence(Types::Sequence)
+        })?
+        .map(|a| LdapPartialAttributeRef::try_from(a?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(LdapSearchResultEntryRef { dn, attributes })
+    }
+}
+
+impl<'a> TryFrom<Element<'a>> for LdapPartialAttributeRef<'a> {
+    type Error = LdapCodecError;
+
+    fn try_from(value: Element<'a>) -> Result<Self, Self::Error> {
+        let mut inner = decode_field(Some(Ok(value)), "PartialAttribute", "", |e| {
+            e.sequence(Types::Sequence)
+        })?;
+
+        let atype = decode_field(inner.next(), "PartialAttribute", "type", Element::string)?;
+
+        let vals = decode_field(inner.next(), "PartialAttribute", "vals", |e| {
+            e.sequence(Types::Set)
+        })?
+        .map(|v| decode_field(Some(v), "PartialAttribute", "vals", Element::octets))
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(LdapPartialAttributeRef { atype, vals })
+    }
+}
+
+impl LdapMsgRef<'_> {
+    /// Convert to an owned LdapMsg, with the limits that LdapCodec would
+    /// apply to the same message. Pass the limits of the `LdapFrameCodec`
+    /// the frame came from.
+    pub fn into_owned(self, limits: &LdapLimits) -> Result<LdapMsg, LdapCodecError> {
+        let LdapMsgRef { msgid, op, ctrl } = self;
+        let op = match op {
+            LdapOpRef::SearchResultEntry(e) => {
+                e.check_limits(limits)?;
+                LdapOp::SearchResultEntry(e.into())
+            }
+            LdapOpRef::Other(raw) => {
+                ber_check_op_limits(raw, limits)?;
+                match parse_tag(raw) {
+                    IResult::Done([], tag) => LdapOp::try_from(tag)?,
+                    _ => return Err(LdapCodecError::Ber),
+                }
+            }
+        };
+        Ok(LdapMsg {
+            msgid,
+            op,
+            ctrl: ctrl.into_iter().map(LdapControl::from).collect(),
+        })
+    }
+}
+
+impl LdapSearchResultEntryRef<'_> {
+    fn check_limits(&self, limits: &LdapLimits) -> Result<(), LdapProtoError> {
+        check_count(
+            self.attributes.len(),
+            "SearchResultEntry",
+            "attributes",
+            limits.max_attributes,
+        )?;
+        self.attributes.iter().try_for_each(|a| {
+            check_count(
+                a.vals.len(),
+                "PartialAttribute",
+                "vals",
+                limits.max_values_per_attribute,
+            )
+        })
+    }
+}
+
+impl TryFrom<LdapMsgRef<'_>> for LdapMsg {
+    type Error = LdapCodecError;
+
+    /// Convert with the default limits, see `LdapMsgRef::into_owned`.
+    fn try_from(value: LdapMsgRef<'_>) -> Result<Self, Self::Error> {
+        value.into_owned(&LdapLimits::default())
+    }
+}
+
+impl From<LdapControlRef<'_>> for LdapControl {
+    fn from(value: LdapControlRef<'_>) -> Self {
+        LdapControl {
+            oid: value.oid.to_string(),
+            criticality: value.criticality,
+            value: value.value.map(Vec::from),
+        }
+    }
+}
+
+impl From<LdapSearchResultEntryRef<'_>> for LdapSearchResultEntry {
+    fn from(value: LdapSearchResultEntryRef<'_>) -> Self {
+        LdapSearchResultEntry {
+            dn: value.dn.to_string(),
+            attributes: value.attributes.into_iter().map(|a| a.into()).collect(),
+        }
+    }
+}
+
+impl From<LdapPartialAttributeRef<'_>> for LdapPartialAttribute {
+    fn from(value: LdapPartialAttributeRef<'_>) -> Self {
+        LdapPartialAttribute {
+            atype: value.atype.to_string(),
+            vals: value.vals.into_iter().map(Vec::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{LdapAddRequest, LdapResult, LdapResultCode};
+    use crate::{LdapCodec, LdapFrameCodec};
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn frame(msg: LdapMsg) -> bytes::Bytes {
+        let mut buf = BytesMut::new();
+        LdapCodec::default()
+            .encode(msg, &mut buf)
+            .expect("failed to encode");
+        LdapFrameCodec::default()
+            .decode(&mut buf)
+            .expect("failed to frame")
+            .expect("incomplete frame")
+    }
+
+    #[test]
+    fn test_borrowed_search_result_entry() {
+        let msg = LdapMsg {
+            msgid: 233,
+            op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                attributes: vec![
+                    LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec![Vec::from("demo")],
+                    },
+                    LdapPartialAttribute {
+                        atype: "objectClass".to_string(),
+                        vals: vec![Vec::from("top"), Vec::from("person")],
+                    },
+                ],
+            }),
+            ctrl: vec![LdapControl {
+                oid: "1.2.3.4".to_string(),
+                criticality: true,
+                value: Some(vec![0, 1, 2]),
+            }],
+        };
+        let frame = frame(msg.clone());
+        let msgref = LdapMsgRef::try_from(&frame[..]).expect("failed to decode");
+
+        assert_eq!(msgref.msgid, 233);
+        assert_eq!(msgref.ctrl[0].oid, "1.2.3.4");
+        match &msgref.op {
+            LdapOpRef::SearchResultEntry(e) => {
+                assert_eq!(e.dn, "cn=demo,dc=example,dc=com");
+                assert_eq!(e.attributes[1].vals, vec![&b"top"[..], &b"person"[..]]);
+                // The values are slices of the frame, not copies.
+                let range = frame.as_ptr_range();
+                assert!(range.contains(&e.attributes[1].vals[1].as_ptr()));
+            }
+            op => panic!("unexpected op {:?}", op),
+        }
+
+        assert_eq!(LdapMsg::try_from(msgref).expect("failed to convert"), msg);
+    }
+
+    #[test]
+    fn test_borrowed_other() {
+        let msg = LdapMsg::new(
+            1,
+            LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+        );
+        let frame = frame(msg.clone());
+        let msgref = LdapMsgRef::try_from(&frame[..]).expect("failed to decode");
+        assert!(matches!(msgref.op, LdapOpRef::Other(_)));
+        assert_eq!(LdapMsg::try_from(msgref).expect("failed to convert"), msg);
+
+        // Frames can be passed on as they are.
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(frame.clone(), &mut buf)
+            .expect("failed to encode");
+        assert_eq!(&buf[..], &frame[..]);
+
+        // And a frame that isn't a whole message is rejected.
+        assert!(LdapMsgRef::try_from(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_borrowed_duplicate_criticality() {
+        // An unbind with a control that has criticality FALSE, then another
+        // BOOLEAN of either value.
+        for second in [0x00, 0xff] {
+            let frame = [
+                &b"\x30\x14\x02\x01\x01\x42\x00\xa0\x0d\x30\x0b\x04\x031.2\x01\x01\x00\x01\x01"[..],
+                &[second],
+            ]
+            .concat();
+            assert!(matches!(
+                LdapMsgRef::try_from(&frame[..]),
+                Err(LdapCodecError::Proto(LdapProtoError::Unexpected {
+                    structure: "Control",
+                    id: 1,
+                    ..
+                }))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_borrowed_limits() {
+        let limits = LdapLimits {
+            max_attributes: 1,
+            max_values_per_attribute: 1,
+            ..Default::default()
+        };
+        let attr = |n| LdapPartialAttribute {
+            atype: "cn".to_string(),
+            vals: vec![Vec::from("demo"); n],
+        };
+        let entry = |attributes| {
+            LdapMsg::new(
+                1,
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=demo,dc=example,dc=com".to_string(),
+                    attributes,
+                }),
+            )
+        };
+        let convert = |msg: LdapMsg| {
+            let frame = frame(msg);
+            LdapMsgRef::try_from(&frame[..])
+                .expect("failed to decode")
+                .into_owned(&limits)
+        };
+
+        assert!(convert(entry(vec![attr(1)])).is_ok());
+        assert!(matches!(
+            convert(entry(vec![attr(1), attr(1)])),
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                structure: "SearchResultEntry",
+                field: "attributes",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            convert(entry(vec![attr(2)])),
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                structure: "PartialAttribute",
+                field: "vals",
+                ..
+            }))
+        ));
+
+        // Ops that are left encoded get the same checks when decoded.
+        let add = LdapMsg::new(
+            1,
+            LdapOp::AddRequest(LdapAddRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                attributes: vec![attr(1), attr(1)],
+            }),
+        );
+        assert!(matches!(
+            convert(add),
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                structure: "AddRequest",
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,954 @@
+// A direct BER encoder for the protocol types.
+//
+// The Tag conversions in proto build an owned tree of Tags, which lber then
+// encodes by writing every constructed element into a temporary Vec before it
+// knows the length. Here we measure the message first, recording the length
+// of each element as we go, and then write it straight into the output buffer
+// with no intermediate allocations. Each length is only computed once, in the
+// measuring pass, so encoding is linear in the size of the message however
+// deeply it nests.
+//
+// The output is byte-identical to the lber path for everything except
+// negative integers, which lber doesn't always encode as minimal two's
+// complement (see int_len). The codec tests check this for every message
+// they round trip, and the negative encodings are pinned here.
+
+use crate::proto::*;
+use bytes::{BufMut, BytesMut};
+use lber::common::TagClass;
+use lber::universal::Types;
+use std::slice;
+
+/// The content lengths from the measuring pass, in the order the elements
+/// are written.
+type Lengths<'a> = slice::Iter<'a, usize>;
+
+/// A value that encodes as a complete BER element.
+pub(crate) trait BerEncode {
+    /// The class, id and whether the element is constructed.
+    fn tag(&self) -> (TagClass, u64, bool);
+
+    /// The length of the contents, without the identifier or length octets.
+    /// Anything measured along the way records its length in `lens`.
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize;
+
+    /// Write the contents, taking the lengths recorded by measure_content.
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths);
+
+    /// The length of the whole element. Primitive elements can override
+    /// this and write_element together to skip recording their length.
+    fn measure(&self, lens: &mut Vec<usize>) -> usize {
+        measure_with(lens, |lens| self.measure_content(lens))
+    }
+
+    fn write_element(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        let (class, id, constructed) = self.tag();
+        write_header(buf, class, constructed, id, next_len(lens));
+        self.write_content(buf, lens);
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        let mut lens = Vec::new();
+        buf.reserve(self.measure(&mut lens));
+        let mut lens = lens.iter();
+        self.write_element(buf, &mut lens);
+        debug_assert!(lens.next().is_none());
+    }
+}
+
+/// The contents of a constructed element whose tag is decided by where it
+/// is used, such as the body of a protocolOp.
+trait BerContent {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize;
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths);
+}
+
+fn length_len(len: usize) -> usize {
+    let mut count = 1;
+    if len >= 128 {
+        let mut rem = len;
+        while rem > 0 {
+            count += 1;
+            rem >>= 8;
+        }
+    }
+    count
+}
+
+// Every tag we send has an id below 31, so the identifier is a single octet.
+fn tlv_len(content_len: usize) -> usize {
+    1 + length_len(content_len) + content_len
+}
+
+// Record the content length measured by `f`, ahead of anything it records
+// itself, so the lengths come out in the order the headers are written.
+fn measure_with<F: FnOnce(&mut Vec<usize>) -> usize>(lens: &mut Vec<usize>, f: F) -> usize {
+    let slot = lens.len();
+    lens.push(0);
+    let len = f(lens);
+    lens[slot] = len;
+    tlv_len(len)
+}
+
+fn next_len(lens: &mut Lengths) -> usize {
+    *lens.next().expect("element was not measured")
+}
+
+fn write_header(buf: &mut BytesMut, class: TagClass, constructed: bool, id: u64, len: usize) {
+    debug_assert!(id < 31);
+    buf.put_u8((class as u8) << 6 | (constructed as u8) << 5 | id as u8);
+    if len < 128 {
+        buf.put_u8(len as u8);
+    } else {
+        let count = length_len(len) - 1;
+        buf.put_u8(0x80 | count as u8);
+        buf.put_uint(len as u64, count);
+    }
+}
+
+// The minimal two's complement length. lber gets this wrong for some negative
+// values (-129 is sent as 0x7f), which we don't reproduce, but no value that
+// it encodes correctly comes out differently.
+fn int_len(v: i64) -> usize {
+    let mut len = 8;
+    while len > 1 {
+        let top = v >> (8 * len - 9);
+        if top == 0 || top == -1 {
+            len -= 1;
+        } else {
+            break;
+        }
+    }
+    len
+}
+
+fn int_tlv_len(v: i64) -> usize {
+    tlv_len(int_len(v))
+}
+
+fn write_int(buf: &mut BytesMut, class: TagClass, id: u64, v: i64) {
+    let len = int_len(v);
+    write_header(buf, class, false, id, len);
+    buf.put_int(v, len);
+}
+
+fn write_bool(buf: &mut BytesMut, class: TagClass, id: u64, v: bool) {
+    write_header(buf, class, false, id, 1);
+    buf.put_u8(if v { 0xFF } else { 0x00 });
+}
+
+fn write_octets(buf: &mut BytesMut, class: TagClass, id: u64, v: &[u8]) {
+    write_header(buf, class, false, id, v.len());
+    buf.put_slice(v);
+}
+
+fn measure_constructed<T: BerContent + ?Sized>(v: &T, lens: &mut Vec<usize>) -> usize {
+    measure_with(lens, |lens| v.measure_content(lens))
+}
+
+fn write_constructed<T: BerContent + ?Sized>(
+    buf: &mut BytesMut,
+    class: TagClass,
+    id: u64,
+    v: &T,
+    lens: &mut Lengths,
+) {
+    write_header(buf, class, true, id, next_len(lens));
+    v.write_content(buf, lens);
+}
+
+fn optional_len<T: BerEncode>(v: &Option<T>, lens: &mut Vec<usize>) -> usize {
+    v.as_ref().map_or(0, |v| v.measure(lens))
+}
+
+// A SEQUENCE OF or SET OF is just its elements back to back.
+impl<T: BerEncode> BerContent for [T] {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.iter().map(|v| v.measure(lens)).sum()
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.iter().for_each(|v| v.write_element(buf, lens))
+    }
+}
+
+// An empty SEQUENCE OF is left out entirely where it is OPTIONAL.
+fn optional_seq_len<T: BerEncode>(v: &[T], lens: &mut Vec<usize>) -> usize {
+    if v.is_empty() {
+        0
+    } else {
+        measure_constructed(v, lens)
+    }
+}
+
+// Octet strings are the bulk of most messages, and their length is already
+// known, so they don't record it. The optional fields sent with context tags
+// rely on this, as they are measured here but written with write_octets.
+impl BerEncode for String {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::OctetString as u64, false)
+    }
+
+    fn measure_content(&self, _lens: &mut Vec<usize>) -> usize {
+        self.len()
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        buf.put_slice(self.as_bytes())
+    }
+
+    fn measure(&self, _lens: &mut Vec<usize>) -> usize {
+        tlv_len(self.len())
+    }
+
+    fn write_element(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        write_octets(
+            buf,
+            TagClass::Universal,
+            Types::OctetString as u64,
+            self.as_bytes(),
+        )
+    }
+}
+
+impl BerEncode for Vec<u8> {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::OctetString as u64, false)
+    }
+
+    fn measure_content(&self, _lens: &mut Vec<usize>) -> usize {
+        self.len()
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        buf.put_slice(self)
+    }
+
+    fn measure(&self, _lens: &mut Vec<usize>) -> usize {
+        tlv_len(self.len())
+    }
+
+    fn write_element(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        write_octets(buf, TagClass::Universal, Types::OctetString as u64, self)
+    }
+}
+
+impl BerEncode for LdapMsg {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        int_tlv_len(self.msgid as i64) + self.op.measure(lens) + optional_seq_len(&self.ctrl, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        write_int(
+            buf,
+            TagClass::Universal,
+            Types::Integer as u64,
+            self.msgid as i64,
+        );
+        self.op.write_element(buf, lens);
+        if !self.ctrl.is_empty() {
+            write_constructed(buf, TagClass::Context, 0, self.ctrl.as_slice(), lens);
+        }
+    }
+}
+
+impl BerEncode for LdapControl {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.oid.measure(lens)
+            + if self.criticality { tlv_len(1) } else { 0 }
+            + optional_len(&self.value, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.oid.write_element(buf, lens);
+        // DEFAULT FALSE, so we only need to send this when it's true.
+        if self.criticality {
+            write_bool(buf, TagClass::Universal, Types::Boolean as u64, true);
+        }
+        if let Some(v) = &self.value {
+            v.write_element(buf, lens);
+        }
+    }
+}
+
+impl BerEncode for LdapOp {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        let (id, constructed) = match self {
+            LdapOp::BindRequest(_) => (0, true),
+            LdapOp::BindResponse(_) => (1, true),
+            LdapOp::UnbindRequest => (2, false),
+            LdapOp::SearchRequest(_) => (3, true),
+            LdapOp::SearchResultEntry(_) => (4, true),
+            LdapOp::SearchResultDone(_) => (5, true),
+            LdapOp::SearchResultReference(_) => (19, true),
+            LdapOp::ModifyRequest(_) => (6, true),
+            LdapOp::ModifyResponse(_) => (7, true),
+            LdapOp::AddRequest(_) => (8, true),
+            LdapOp::AddResponse(_) => (9, true),
+            LdapOp::DelRequest(_) => (10, false),
+            LdapOp::DelResponse(_) => (11, true),
+            LdapOp::ModifyDNRequest(_) => (12, true),
+            LdapOp::ModifyDNResponse(_) => (13, true),
+            LdapOp::CompareRequest(_) => (14, true),
+            LdapOp::CompareResponse(_) => (15, true),
+            LdapOp::AbandonRequest(_) => (16, false),
+            LdapOp::ExtendedRequest(_) => (23, true),
+            LdapOp::ExtendedResponse(_) => (24, true),
+            LdapOp::IntermediateResponse(_) => (25, true),
+        };
+        (TagClass::Application, id, constructed)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        match self {
+            LdapOp::BindRequest(v) => v.measure_content(lens),
+            LdapOp::BindResponse(v) => v.measure_content(lens),
+            LdapOp::UnbindRequest => 0,
+            LdapOp::SearchRequest(v) => v.measure_content(lens),
+            LdapOp::SearchResultEntry(v) => v.measure_content(lens),
+            LdapOp::SearchResultReference(v) => v.measure_content(lens),
+            LdapOp::ModifyRequest(v) => v.measure_content(lens),
+            LdapOp::AddRequest(v) => v.measure_content(lens),
+            LdapOp::DelRequest(v) => v.len(),
+            LdapOp::ModifyDNRequest(v) => v.measure_content(lens),
+            LdapOp::CompareRequest(v) => v.measure_content(lens),
+            LdapOp::AbandonRequest(v) => int_len(*v as i64),
+            LdapOp::ExtendedRequest(v) => v.measure_content(lens),
+            LdapOp::ExtendedResponse(v) => v.measure_content(lens),
+            LdapOp::IntermediateResponse(v) => v.measure_content(lens),
+            LdapOp::SearchResultDone(v)
+            | LdapOp::ModifyResponse(v)
+            | LdapOp::AddResponse(v)
+            | LdapOp::DelResponse(v)
+            | LdapOp::ModifyDNResponse(v)
+            | LdapOp::CompareResponse(v) => v.measure_content(lens),
+        }
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        match self {
+            LdapOp::BindRequest(v) => v.write_content(buf, lens),
+            LdapOp::BindResponse(v) => v.write_content(buf, lens),
+            LdapOp::UnbindRequest => {}
+            LdapOp::SearchRequest(v) => v.write_content(buf, lens),
+            LdapOp::SearchResultEntry(v) => v.write_content(buf, lens),
+            LdapOp::SearchResultReference(v) => v.write_content(buf, lens),
+            LdapOp::ModifyRequest(v) => v.write_content(buf, lens),
+            LdapOp::AddRequest(v) => v.write_content(buf, lens),
+            LdapOp::DelRequest(v) => buf.put_slice(v.as_bytes()),
+            LdapOp::ModifyDNRequest(v) => v.write_content(buf, lens),
+            LdapOp::CompareRequest(v) => v.write_content(buf, lens),
+            LdapOp::AbandonRequest(v) => buf.put_int(*v as i64, int_len(*v as i64)),
+            LdapOp::ExtendedRequest(v) => v.write_content(buf, lens),
+            LdapOp::ExtendedResponse(v) => v.write_content(buf, lens),
+            LdapOp::IntermediateResponse(v) => v.write_content(buf, lens),
+            LdapOp::SearchResultDone(v)
+            | LdapOp::ModifyResponse(v)
+            | LdapOp::AddResponse(v)
+            | LdapOp::DelResponse(v)
+            | LdapOp::ModifyDNResponse(v)
+            | LdapOp::CompareResponse(v) => v.write_content(buf, lens),
+        }
+    }
+}
+
+impl BerEncode for LdapBindCred {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        match self {
+            LdapBindCred::Simple(_) => (TagClass::Context, 0, false),
+            LdapBindCred::Sasl { .. } => (TagClass::Context, 3, true),
+        }
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        match self {
+            LdapBindCred::Simple(pw) => pw.len(),
+            LdapBindCred::Sasl {
+                mechanism,
+                credentials,
+            } => mechanism.measure(lens) + optional_len(credentials, lens),
+        }
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        match self {
+            LdapBindCred::Simple(pw) => buf.put_slice(pw.as_bytes()),
+            LdapBindCred::Sasl {
+                mechanism,
+                credentials,
+            } => {
+                mechanism.write_element(buf, lens);
+                if let Some(c) = credentials {
+                    c.write_element(buf, lens);
+                }
+            }
+        }
+    }
+}
+
+impl BerContent for LdapBindRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        int_tlv_len(self.version as i64) + self.dn.measure(lens) + self.cred.measure(lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        write_int(
+            buf,
+            TagClass::Universal,
+            Types::Integer as u64,
+            self.version as i64,
+        );
+        self.dn.write_element(buf, lens);
+        self.cred.write_element(buf, lens);
+    }
+}
+
+impl BerContent for LdapResult {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        int_tlv_len(i64::from(&self.code))
+            + self.matcheddn.measure(lens)
+            + self.message.measure(lens)
+            + optional_seq_len(&self.referral, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        write_int(
+            buf,
+            TagClass::Universal,
+            Types::Enumerated as u64,
+            i64::from(&self.code),
+        );
+        self.matcheddn.write_element(buf, lens);
+        self.message.write_element(buf, lens);
+        if !self.referral.is_empty() {
+            write_constructed(buf, TagClass::Context, 3, self.referral.as_slice(), lens);
+        }
+    }
+}
+
+impl BerContent for LdapBindResponse {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.res.measure_content(lens) + optional_len(&self.saslcreds, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.res.write_content(buf, lens);
+        if let Some(sc) = &self.saslcreds {
+            write_octets(buf, TagClass::Context, 7, sc);
+        }
+    }
+}
+
+impl BerEncode for LdapFilter {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        let (id, constructed) = match self {
+            LdapFilter::And(_) => (0, true),
+            LdapFilter::Or(_) => (1, true),
+            LdapFilter::Not(_) => (2, true),
+            LdapFilter::Equality(_, _) => (3, true),
+            LdapFilter::Substring(_, _) => (4, true),
+            LdapFilter::GreaterOrEqual(_, _) => (5, true),
+            LdapFilter::LessOrEqual(_, _) => (6, true),
+            LdapFilter::Present(_) => (7, false),
+            LdapFilter::Approx(_, _) => (8, true),
+            LdapFilter::Extensible { .. } => (9, true),
+        };
+        (TagClass::Context, id, constructed)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        match self {
+            LdapFilter::And(vf) | LdapFilter::Or(vf) => vf.measure_content(lens),
+            LdapFilter::Not(f) => f.measure(lens),
+            LdapFilter::Equality(a, v)
+            | LdapFilter::GreaterOrEqual(a, v)
+            | LdapFilter::LessOrEqual(a, v)
+            | LdapFilter::Approx(a, v) => a.measure(lens) + v.measure(lens),
+            LdapFilter::Substring(t, f) => t.measure(lens) + f.measure(lens),
+            LdapFilter::Present(a) => a.len(),
+            LdapFilter::Extensible {
+                rule,
+                atype,
+                value,
+                dn_attributes,
+            } => {
+                optional_len(rule, lens)
+                    + optional_len(atype, lens)
+                    + value.measure(lens)
+                    + if *dn_attributes { tlv_len(1) } else { 0 }
+            }
+        }
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        match self {
+            LdapFilter::And(vf) | LdapFilter::Or(vf) => vf.write_content(buf, lens),
+            LdapFilter::Not(f) => f.write_element(buf, lens),
+            LdapFilter::Equality(a, v)
+            | LdapFilter::GreaterOrEqual(a, v)
+            | LdapFilter::LessOrEqual(a, v)
+            | LdapFilter::Approx(a, v) => {
+                a.write_element(buf, lens);
+                v.write_element(buf, lens);
+            }
+            LdapFilter::Substring(t, f) => {
+                t.write_element(buf, lens);
+                f.write_element(buf, lens);
+            }
+            LdapFilter::Present(a) => buf.put_slice(a.as_bytes()),
+            LdapFilter::Extensible {
+                rule,
+                atype,
+                value,
+                dn_attributes,
+            } => {
+                if let Some(r) = rule {
+                    write_octets(buf, TagClass::Context, 1, r.as_bytes());
+                }
+                if let Some(a) = atype {
+                    write_octets(buf, TagClass::Context, 2, a.as_bytes());
+                }
+                write_octets(buf, TagClass::Context, 3, value);
+                // DEFAULT FALSE, so we only need to send this when it's true.
+                if *dn_attributes {
+                    write_bool(buf, TagClass::Context, 4, true);
+                }
+            }
+        }
+    }
+}
+
+impl BerEncode for LdapSubstringFilter {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        optional_len(&self.initial, lens)
+            + self.any.measure_content(lens)
+            + optional_len(&self.final_, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        let class = TagClass::Context;
+        if let Some(s) = &self.initial {
+            write_octets(buf, class, 0, s);
+        }
+        for s in &self.any {
+            write_octets(buf, class, 1, s);
+        }
+        if let Some(s) = &self.final_ {
+            write_octets(buf, class, 2, s);
+        }
+    }
+}
+
+impl BerContent for LdapSearchRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.base.measure(lens)
+            + int_tlv_len(self.scope.clone() as i64)
+            + int_tlv_len(self.aliases.clone() as i64)
+            + int_tlv_len(self.sizelimit as i64)
+            + int_tlv_len(self.timelimit as i64)
+            + tlv_len(1)
+            + self.filter.measure(lens)
+            + measure_constructed(self.attrs.as_slice(), lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        let class = TagClass::Universal;
+        self.base.write_element(buf, lens);
+        write_int(
+            buf,
+            class,
+            Types::Enumerated as u64,
+            self.scope.clone() as i64,
+        );
+        write_int(
+            buf,
+            class,
+            Types::Enumerated as u64,
+            self.aliases.clone() as i64,
+        );
+        write_int(buf, class, Types::Integer as u64, self.sizelimit as i64);
+        write_int(buf, class, Types::Integer as u64, self.timelimit as i64);
+        write_bool(buf, class, Types::Boolean as u64, self.typesonly);
+        self.filter.write_element(buf, lens);
+        write_constructed(
+            buf,
+            class,
+            Types::Sequence as u64,
+            self.attrs.as_slice(),
+            lens,
+        );
+    }
+}
+
+impl BerEncode for LdapPartialAttribute {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.atype.measure(lens) + measure_constructed(self.vals.as_slice(), lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.atype.write_element(buf, lens);
+        write_constructed(
+            buf,
+            TagClass::Universal,
+            Types::Set as u64,
+            self.vals.as_slice(),
+            lens,
+        );
+    }
+}
+
+impl BerContent for LdapSearchResultEntry {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.dn.measure(lens) + measure_constructed(self.attributes.as_slice(), lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.dn.write_element(buf, lens);
+        write_constructed(
+            buf,
+            TagClass::Universal,
+            Types::Sequence as u64,
+            self.attributes.as_slice(),
+            lens,
+        );
+    }
+}
+
+impl BerContent for LdapAddRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.dn.measure(lens) + measure_constructed(self.attributes.as_slice(), lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.dn.write_element(buf, lens);
+        write_constructed(
+            buf,
+            TagClass::Universal,
+            Types::Sequence as u64,
+            self.attributes.as_slice(),
+            lens,
+        );
+    }
+}
+
+impl BerEncode for LdapModify {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        int_tlv_len(self.operation.clone() as i64) + self.modification.measure(lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        write_int(
+            buf,
+            TagClass::Universal,
+            Types::Enumerated as u64,
+            self.operation.clone() as i64,
+        );
+        self.modification.write_element(buf, lens);
+    }
+}
+
+impl BerContent for LdapModifyRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.dn.measure(lens) + measure_constructed(self.changes.as_slice(), lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.dn.write_element(buf, lens);
+        write_constructed(
+            buf,
+            TagClass::Universal,
+            Types::Sequence as u64,
+            self.changes.as_slice(),
+            lens,
+        );
+    }
+}
+
+impl BerContent for LdapModifyDNRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.dn.measure(lens)
+            + self.newrdn.measure(lens)
+            + tlv_len(1)
+            + optional_len(&self.new_superior, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.dn.write_element(buf, lens);
+        self.newrdn.write_element(buf, lens);
+        write_bool(
+            buf,
+            TagClass::Universal,
+            Types::Boolean as u64,
+            self.deleteoldrdn,
+        );
+        if let Some(ns) = &self.new_superior {
+            write_octets(buf, TagClass::Context, 0, ns.as_bytes());
+        }
+    }
+}
+
+impl BerContent for LdapCompareRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.dn.measure(lens)
+            + measure_with(lens, |lens| {
+                self.atype.measure(lens) + self.val.measure(lens)
+            })
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.dn.write_element(buf, lens);
+        write_header(
+            buf,
+            TagClass::Universal,
+            true,
+            Types::Sequence as u64,
+            next_len(lens),
+        );
+        self.atype.write_element(buf, lens);
+        self.val.write_element(buf, lens);
+    }
+}
+
+impl BerContent for LdapExtendedRequest {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        tlv_len(self.name.len()) + optional_len(&self.value, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        write_octets(buf, TagClass::Context, 0, self.name.as_bytes());
+        if let Some(v) = &self.value {
+            write_octets(buf, TagClass::Context, 1, v);
+        }
+    }
+}
+
+impl BerContent for LdapExtendedResponse {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        self.res.measure_content(lens)
+            + optional_len(&self.name, lens)
+            + optional_len(&self.value, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        self.res.write_content(buf, lens);
+        if let Some(n) = &self.name {
+            write_octets(buf, TagClass::Context, 10, n.as_bytes());
+        }
+        if let Some(v) = &self.value {
+            write_octets(buf, TagClass::Context, 11, v);
+        }
+    }
+}
+
+impl BerContent for LdapIntermediateResponse {
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        optional_len(&self.name, lens) + optional_len(&self.value, lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, _lens: &mut Lengths) {
+        if let Some(n) = &self.name {
+            write_octets(buf, TagClass::Context, 0, n.as_bytes());
+        }
+        if let Some(v) = &self.value {
+            write_octets(buf, TagClass::Context, 1, v);
+        }
+    }
+}
+
+impl BerEncode for LdapPagedResults {
+    fn tag(&self) -> (TagClass, u64, bool) {
+        (TagClass::Universal, Types::Sequence as u64, true)
+    }
+
+    fn measure_content(&self, lens: &mut Vec<usize>) -> usize {
+        int_tlv_len(self.size as i64) + self.cookie.measure(lens)
+    }
+
+    fn write_content(&self, buf: &mut BytesMut, lens: &mut Lengths) {
+        write_int(
+            buf,
+            TagClass::Universal,
+            Types::Integer as u64,
+            self.size as i64,
+        );
+        self.cookie.write_element(buf, lens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BerEncode;
+    use crate::proto::*;
+    use bytes::BytesMut;
+    use lber::parse::parse_tag;
+    use lber::structure::StructureTag;
+    use lber::structures::{ASNTag, Tag};
+    use lber::IResult;
+    use std::convert::TryFrom;
+
+    fn assert_identical(msg: LdapMsg) {
+        let mut buf = BytesMut::new();
+        msg.encode(&mut buf);
+        assert_eq!(buf.len(), msg.measure(&mut Vec::new()));
+
+        let mut tag_buf = BytesMut::new();
+        lber::write::encode_into(&mut tag_buf, StructureTag::from(msg)).expect("failed to encode");
+        assert_eq!(buf, tag_buf);
+    }
+
+    #[test]
+    fn test_encode_integer_boundaries() {
+        // lber only encodes non-negative values correctly, so only those are
+        // compared.
+        for v in [
+            0,
+            1,
+            127,
+            128,
+            255,
+            256,
+            32767,
+            32768,
+            65535,
+            65536,
+            8388607,
+            8388608,
+            i32::MAX,
+        ] {
+            assert_identical(LdapMsg::new(v, LdapOp::AbandonRequest(v)));
+        }
+    }
+
+    #[test]
+    fn test_encode_negative_integers() {
+        // These are the minimal two's complement forms from X.690 8.3, which
+        // is where we intentionally differ from lber.
+        for (v, bytes) in [
+            (-1, vec![0xff]),
+            (-128, vec![0x80]),
+            (-129, vec![0xff, 0x7f]),
+            (-256, vec![0xff, 0x00]),
+            (-32769, vec![0xff, 0x7f, 0xff]),
+            (i32::MIN, vec![0x80, 0x00, 0x00, 0x00]),
+        ] {
+            let mut buf = BytesMut::new();
+            LdapOp::AbandonRequest(v).encode(&mut buf);
+            assert_eq!(buf[0], 0x50);
+            assert_eq!(buf[1] as usize, bytes.len());
+            assert_eq!(&buf[2..], bytes.as_slice());
+
+            // And they read back as the same value.
+            let mut buf = BytesMut::new();
+            LdapMsg::new(1, LdapOp::AbandonRequest(v)).encode(&mut buf);
+            let tag = match parse_tag(&buf) {
+                IResult::Done([], tag) => tag,
+                _ => panic!("failed to parse"),
+            };
+            let msg = LdapMsg::try_from(tag).expect("failed to decode");
+            assert!(msg.op == LdapOp::AbandonRequest(v));
+        }
+
+        // lber sends -129 as 0x7f, which reads back as 127.
+        let mut tag_buf = BytesMut::new();
+        let tag = StructureTag::from(LdapMsg::new(1, LdapOp::AbandonRequest(-129)));
+        lber::write::encode_into(&mut tag_buf, tag).expect("failed to encode");
+        let mut buf = BytesMut::new();
+        LdapMsg::new(1, LdapOp::AbandonRequest(-129)).encode(&mut buf);
+        assert!(buf != tag_buf);
+    }
+
+    #[test]
+    fn test_encode_measures_once() {
+        // Each constructed element is measured once, however deeply it is
+        // nested, so there is exactly one length for each of them.
+        let depth = 100;
+        let filter = (0..depth).fold(LdapFilter::Present("cn".to_string()), |f, _| {
+            LdapFilter::Not(Box::new(f))
+        });
+        let msg = LdapMsg::new(
+            1,
+            LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter,
+                attrs: vec!["cn".to_string()],
+            }),
+        );
+        let mut lens = Vec::new();
+        msg.measure(&mut lens);
+        // The message, the op, every filter and the attribute list.
+        assert_eq!(lens.len(), 2 + depth + 1 + 1);
+        assert_identical(msg);
+    }
+
+    #[test]
+    fn test_encode_substring_filter() {
+        // (cn=ab*cd*ef), with the choices as context tags [0], [1] and [2].
+        let bytes = b"\xa4\x12\x04\x02cn\x30\x0c\x80\x02ab\x81\x02cd\x82\x02ef";
+        let filter = LdapFilter::Substring(
+            "cn".to_string(),
+            LdapSubstringFilter {
+                initial: Some(Vec::from("ab")),
+                any: vec![Vec::from("cd")],
+                final_: Some(Vec::from("ef")),
+            },
+        );
+
+        let mut buf = BytesMut::new();
+        filter.encode(&mut buf);
+        assert_eq!(&buf[..], &bytes[..]);
+
+        let mut tag_buf = BytesMut::new();
+        let tag = Tag::from(filter.clone()).into_structure();
+        lber::write::encode_into(&mut tag_buf, tag).expect("failed to encode");
+        assert_eq!(&tag_buf[..], &bytes[..]);
+
+        let tag = match parse_tag(&bytes[..]) {
+            IResult::Done([], tag) => tag,
+            _ => panic!("failed to parse"),
+        };
+        assert_eq!(LdapFilter::try_from(tag).expect("failed to decode"), filter);
+    }
+
+    #[test]
+    fn test_encode_long_lengths() {
+        // Values either side of each long form length boundary.
+        for size in [127, 128, 255, 256, 65535, 65536] {
+            assert_identical(LdapMsg::new(
+                1,
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=demo,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "jpegPhoto".to_string(),
+                        vals: vec![vec![0x5a; size], vec![0x5b; size / 2]],
+                    }],
+                }),
+            ));
+        }
+    }
+}
@@ -1,45 +1,360 @@
+pub mod borrowed;
+mod encode;
 pub mod proto;
+#[cfg(feature = "scram")]
+pub mod scram;
 pub mod simple;
 
-use bytes::{Buf, BytesMut};
-use lber::parse::Parser;
-use lber::structure::StructureTag;
-use lber::write as lber_write;
-use lber::{Consumer, ConsumerState, Input, Move};
-use std::convert::TryFrom;
+use bytes::{Bytes, BytesMut};
+use lber::common::TagClass;
+use lber::parse::parse_tag;
+use lber::IResult;
+use std::fmt;
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::proto::LdapMsg;
+use crate::encode::BerEncode;
+
+pub use crate::proto::LdapProtoError;
 pub use crate::simple::*;
 
-pub struct LdapCodec;
+/// Errors from decoding a message off the wire. Once one of these is
+/// returned the stream can not be trusted, and the connection should be
+/// closed.
+#[derive(Debug)]
+pub enum LdapCodecError {
+    Io(io::Error),
+    /// The bytes were not valid BER.
+    Ber,
+    /// The BER was valid, but was not a valid LDAP message.
+    Proto(LdapProtoError),
+    /// An element used the indefinite length form, which LDAP does not allow.
+    /// This is only checked by a strict codec.
+    IndefiniteLength,
+}
+
+impl fmt::Display for LdapCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LdapCodecError::Io(e) => write!(f, "io error: {}", e),
+            LdapCodecError::Ber => write!(f, "invalid ber"),
+            LdapCodecError::Proto(e) => write!(f, "invalid ldap message: {}", e),
+            LdapCodecError::IndefiniteLength => write!(f, "indefinite length not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for LdapCodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LdapCodecError::Io(e) => Some(e),
+            LdapCodecError::Ber => None,
+            LdapCodecError::Proto(e) => Some(e),
+            LdapCodecError::IndefiniteLength => None,
+        }
+    }
+}
+
+impl From<io::Error> for LdapCodecError {
+    fn from(e: io::Error) -> Self {
+        LdapCodecError::Io(e)
+    }
+}
+
+impl From<LdapProtoError> for LdapCodecError {
+    fn from(e: LdapProtoError) -> Self {
+        LdapCodecError::Proto(e)
+    }
+}
+
+/// Bounds on what `LdapCodec` will decode from a peer. Each is checked before
+/// the offending part of a message is buffered or decoded.
+#[derive(Debug, Clone)]
+pub struct LdapLimits {
+    /// The largest message, including its tag and length, in bytes.
+    pub max_pdu_size: usize,
+    /// How deeply search filters may nest. A lone equality filter has a depth
+    /// of one, and each and, or or not around it adds one.
+    pub max_filter_depth: usize,
+    /// The most attributes in a search request, search result entry, add or
+    /// modify.
+    pub max_attributes: usize,
+    /// The most values a single attribute may have.
+    pub max_values_per_attribute: usize,
+}
+
+impl Default for LdapLimits {
+    fn default() -> Self {
+        LdapLimits {
+            max_pdu_size: 4 * 1024 * 1024,
+            max_filter_depth: 32,
+            max_attributes: 1024,
+            max_values_per_attribute: 65536,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LdapCodec {
+    limits: LdapLimits,
+    strict: bool,
+}
+
+impl LdapCodec {
+    pub fn with_limits(limits: LdapLimits) -> Self {
+        LdapCodec {
+            limits,
+            strict: false,
+        }
+    }
+
+    /// In strict mode, messages that are valid BER but that no conforming
+    /// client would send are rejected rather than decoded as best we can.
+    /// This covers indefinite lengths, a msgid outside of 0 .. 2^31-1,
+    /// booleans other than 0x00 and 0xff, and extra elements after the last
+    /// field of a structure.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+/// Read the identifier and length octets at the start of buf, returning the
+/// length of both together and the length of the contents, or None if more
+/// bytes are needed.
+pub(crate) fn ber_header(buf: &[u8]) -> Result<Option<(usize, usize)>, LdapCodecError> {
+    let (tag, len) = match buf {
+        [tag, len, ..] => (*tag, *len),
+        _ => return Ok(None),
+    };
+    // lber can't parse tag numbers above 30, so neither do we.
+    if tag & 0x1f == 0x1f {
+        return Err(LdapCodecError::Ber);
+    }
+    if len & 0x80 == 0 {
+        return Ok(Some((2, len as usize)));
+    }
+    let n = (len & 0x7f) as usize;
+    match buf.get(2..2 + n) {
+        // Saturate rather than overflow, anything that large is over the
+        // limit anyway.
+        Some(octets) => Ok(Some((
+            2 + n,
+            octets.iter().fold(0usize, |l, o| {
+                l.saturating_mul(256).saturating_add(*o as usize)
+            }),
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// What an element is, as far as the limits are concerned. This is worked out
+/// from the element it is within and its position there, so that the limits
+/// can be checked from the raw bytes.
+#[derive(Debug, Clone, Copy)]
+enum Within {
+    Message,
+    Op(u64),
+    /// An and, or or not filter, at this depth.
+    Filter(usize),
+    /// The attributes of a search result entry or add request.
+    Attributes(&'static str),
+    /// The changes of a modify request.
+    Changes,
+    Change,
+    Attribute,
+    /// The attribute selection of a search request.
+    Selection,
+    /// The values of an attribute.
+    Values,
+    Other,
+}
+
+impl Within {
+    /// The element at index within this one.
+    fn child(
+        self,
+        index: usize,
+        class: TagClass,
+        id: u64,
+        limits: &LdapLimits,
+    ) -> Result<Within, LdapProtoError> {
+        Ok(match (self, index, class) {
+            (Within::Message, 1, TagClass::Application) => Within::Op(id),
+            (Within::Op(3), 6, _) => Within::filter(1, class, id, limits)?,
+            (Within::Op(3), 7, _) => Within::Selection,
+            (Within::Op(4), 1, _) => Within::Attributes("SearchResultEntry"),
+            (Within::Op(8), 1, _) => Within::Attributes("AddRequest"),
+            (Within::Op(6), 1, _) => Within::Changes,
+            (Within::Attributes(_), _, _) => Within::Attribute,
+            (Within::Changes, _, _) => Within::Change,
+            (Within::Change, 1, _) => Within::Attribute,
+            (Within::Attribute, 1, _) => Within::Values,
+            (Within::Filter(depth), _, _) => Within::filter(depth + 1, class, id, limits)?,
+            _ => Within::Other,
+        })
+    }
+
+    fn filter(
+        depth: usize,
+        class: TagClass,
+        id: u64,
+        limits: &LdapLimits,
+    ) -> Result<Within, LdapProtoError> {
+        proto::check_count(depth, "Filter", "", limits.max_filter_depth)?;
+        // Only and, or and not contain other filters.
+        Ok(match (class, id) {
+            (TagClass::Context, 0..=2) => Within::Filter(depth),
+            _ => Within::Other,
+        })
+    }
+
+    /// The most elements this one may contain.
+    fn limit(self, limits: &LdapLimits) -> Option<(&'static str, &'static str, usize)> {
+        match self {
+            Within::Attributes(structure) => Some((structure, "attributes", limits.max_attributes)),
+            Within::Changes => Some(("ModifyRequest", "changes", limits.max_attributes)),
+            Within::Selection => Some(("SearchRequest", "attributes", limits.max_attributes)),
+            Within::Values => Some(("PartialAttribute", "vals", limits.max_values_per_attribute)),
+            _ => None,
+        }
+    }
+}
+
+/// Check the pdu, whose outermost element is `top`, against the limits, and
+/// if strict that no element uses the indefinite length form. This walks the
+/// pdu without recursing or building anything, so that it can be done before
+/// the parser (which does both) sees it.
+fn ber_check_limits(
+    pdu: &[u8],
+    top: Within,
+    limits: &LdapLimits,
+    strict: bool,
+) -> Result<(), LdapCodecError> {
+    struct Open {
+        end: usize,
+        within: Within,
+        count: usize,
+    }
+
+    // Outside of filters nothing nests more than six deep (the values of
+    // a modification), and a search puts its filter two deep with a
+    // substring's sequence one further in.
+    let max_depth = std::cmp::max(limits.max_filter_depth.saturating_add(3), 6);
+    // The elements we are inside of.
+    let mut open: Vec<Open> = Vec::new();
+    let mut pos = 0;
+    while pos < pdu.len() {
+        while open.last().is_some_and(|o| pos >= o.end) {
+            open.pop();
+        }
+        let (hdr_len, len) = ber_header(&pdu[pos..])?.ok_or(LdapCodecError::Ber)?;
+        if strict && pdu[pos + 1] == 0x80 {
+            return Err(LdapCodecError::IndefiniteLength);
+        }
+        let class = TagClass::from_u8(pdu[pos] >> 6).ok_or(LdapCodecError::Ber)?;
+        let constructed = pdu[pos] & 0x20 != 0;
+        let id = (pdu[pos] & 0x1f) as u64;
+
+        let within = match open.last_mut() {
+            Some(parent) => {
+                parent.count += 1;
+                if let Some((structure, field, limit)) = parent.within.limit(limits) {
+                    proto::check_count(parent.count, structure, field, limit)?;
+                }
+                parent.within.child(parent.count - 1, class, id, limits)?
+            }
+            None => top,
+        };
+
+        pos += hdr_len;
+        if constructed {
+            open.push(Open {
+                end: pos.saturating_add(len),
+                within,
+                count: 0,
+            });
+            if open.len() > max_depth {
+                return Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                    structure: "LDAPMessage",
+                    field: "",
+                    limit: max_depth,
+                }));
+            }
+        } else {
+            pos = pos.saturating_add(len);
+        }
+    }
+    Ok(())
+}
+
+/// As ber_check_limits, for a protocolOp on its own.
+pub(crate) fn ber_check_op_limits(op: &[u8], limits: &LdapLimits) -> Result<(), LdapCodecError> {
+    let top = match op.first() {
+        Some(b) => {
+            let class = TagClass::from_u8(b >> 6).ok_or(LdapCodecError::Ber)?;
+            Within::Message.child(1, class, (b & 0x1f) as u64, limits)?
+        }
+        None => return Err(LdapCodecError::Ber),
+    };
+    ber_check_limits(op, top, limits, false)
+}
+
+/// Split the next message off of buf once all of it has arrived.
+fn decode_frame(
+    limits: &LdapLimits,
+    strict: bool,
+    buf: &mut BytesMut,
+) -> Result<Option<BytesMut>, LdapCodecError> {
+    // Check how large the peer says the message is before we buffer it.
+    let (hdr_len, len) = match ber_header(buf)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let pdu_size = hdr_len.saturating_add(len);
+    if pdu_size > limits.max_pdu_size {
+        return Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+            structure: "LDAPMessage",
+            field: "",
+            limit: limits.max_pdu_size,
+        }));
+    }
+    if buf.len() < pdu_size {
+        // Make room for some of the rest, but not all of it. The peer hasn't
+        // sent it yet, and may never do so.
+        buf.reserve(std::cmp::min(pdu_size - buf.len(), 8 * 1024));
+        return Ok(None);
+    }
+    ber_check_limits(&buf[..pdu_size], Within::Message, limits, strict)?;
+    Ok(Some(buf.split_to(pdu_size)))
+}
 
 impl Decoder for LdapCodec {
-    type Item = LdapMsg;
-    type Error = io::Error;
+    /// A message that failed to decode, but whose msgid is known, is returned
+    /// as an `Err` item rather than an error so that the connection can be
+    /// kept open.
+    type Item = Result<LdapMsg, LdapMalformedMsg>;
+    type Error = LdapCodecError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // How many bytes to consume?
-        let mut parser = Parser::new();
-        let (size, msg) = match *parser.handle(Input::Element(buf)) {
-            ConsumerState::Continue(_) => return Ok(None),
-            ConsumerState::Error(_e) => {
-                return Err(io::Error::new(io::ErrorKind::Other, "lber parser"))
-            }
-            ConsumerState::Done(size, ref msg) => (size, msg),
+        let frame = match decode_frame(&self.limits, self.strict, buf)? {
+            Some(frame) => frame,
+            None => return Ok(None),
         };
-        // Consume that
-        let size = match size {
-            Move::Await(_) => return Ok(None),
-            Move::Seek(_) => return Err(io::Error::new(io::ErrorKind::Other, "lber seek")),
-            Move::Consume(s) => s,
+        // We have the whole pdu, so it only needs to be parsed the once.
+        let msg = match parse_tag(&frame) {
+            IResult::Done([], msg) => msg,
+            _ => return Err(LdapCodecError::Ber),
         };
-        buf.advance(size);
+        if self.strict {
+            if let Err(malformed) = proto::check_strict(&msg)? {
+                return Ok(Some(Err(malformed)));
+            }
+        }
         // Build the LdapMsg from the Tag
-        LdapMsg::try_from(msg.clone())
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "ldapmsg invalid"))
-            .map(|v| Some(v))
+        LdapMsg::try_from_recoverable(msg)
+            .map_err(LdapCodecError::Proto)
+            .map(Some)
     }
 }
 
@@ -48,8 +363,45 @@ impl Encoder<LdapMsg> for LdapCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> io::Result<()> {
-        let encoded: StructureTag = msg.into();
-        lber_write::encode_into(buf, encoded)?;
+        msg.encode(buf);
+        Ok(())
+    }
+}
+
+/// A codec that only frames messages, leaving them as bytes to be viewed with
+/// `LdapMsgRef` or passed on as they are. The limits are applied just as they
+/// are by `LdapCodec`, since they are checked before anything is decoded.
+#[derive(Debug, Clone, Default)]
+pub struct LdapFrameCodec {
+    limits: LdapLimits,
+}
+
+impl LdapFrameCodec {
+    pub fn with_limits(limits: LdapLimits) -> Self {
+        LdapFrameCodec { limits }
+    }
+
+    /// The limits to apply when converting a frame to an owned `LdapMsg`,
+    /// see `LdapMsgRef::into_owned`.
+    pub fn limits(&self) -> &LdapLimits {
+        &self.limits
+    }
+}
+
+impl Decoder for LdapFrameCodec {
+    type Item = Bytes;
+    type Error = LdapCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_frame(&self.limits, false, buf).map(|f| f.map(BytesMut::freeze))
+    }
+}
+
+impl Encoder<Bytes> for LdapFrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&frame);
         Ok(())
     }
 }
@@ -57,17 +409,32 @@ impl Encoder<LdapMsg> for LdapCodec {
 #[cfg(test)]
 mod tests {
     use crate::proto::*;
-    use crate::LdapCodec;
+    use crate::{LdapCodec, LdapCodecError, LdapLimits};
     use bytes::BytesMut;
+    use lber::common::TagClass;
+    use lber::structure::StructureTag;
+    use std::convert::TryFrom;
     use tokio_util::codec::{Decoder, Encoder};
 
     macro_rules! do_test {
         ($req:expr) => {{
             let mut buf = BytesMut::new();
-            let mut server_codec = LdapCodec;
+            let mut server_codec = LdapCodec::default();
             assert!(server_codec.encode($req.clone(), &mut buf).is_ok());
+            // The direct encoder must match the lber Tag path byte for byte.
+            let mut tag_buf = BytesMut::new();
+            lber::write::encode_into(&mut tag_buf, StructureTag::from($req.clone()))
+                .expect("failed to encode");
+            assert_eq!(buf, tag_buf);
+            // And what we send must satisfy a strict peer.
+            let mut strict_buf = buf.clone();
+            let strict = LdapCodec::default()
+                .strict(true)
+                .decode(&mut strict_buf)
+                .expect("failed to decode strictly");
+            assert!(strict.expect("None found?").as_ref() == Ok(&$req));
             let res = server_codec.decode(&mut buf).expect("failed to decode");
-            let msg = res.expect("None found?");
+            let msg = res.expect("None found?").expect("malformed msg");
             println!("{:?}", msg);
             assert!($req == msg)
         }};
@@ -78,6 +445,7 @@ mod tests {
         do_test!(LdapMsg {
             msgid: 1,
             op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
                 dn: "".to_string(),
                 cred: LdapBindCred::Simple("".to_string()),
             }),
@@ -85,6 +453,111 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_controls() {
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::DelRequest("cn=demo,dc=example,dc=com".to_string()),
+            ctrl: vec![
+                LdapControl {
+                    oid: "1.3.6.1.1.13.1".to_string(),
+                    criticality: true,
+                    value: Some(vec![0x30, 0x03, 0x04, 0x01, 0x2a]),
+                },
+                LdapControl {
+                    oid: "1.2.840.113556.1.4.805".to_string(),
+                    criticality: false,
+                    value: None,
+                },
+            ],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::DelResponse(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![LdapControl {
+                oid: "1.3.6.1.1.13.1".to_string(),
+                criticality: false,
+                value: Some(Vec::new()),
+            }],
+        });
+
+        // A control can't have two criticality fields, whatever the first
+        // one was.
+        for second in [0x00, 0xff] {
+            let mut buf = BytesMut::from(
+                &[
+                    &b"\x30\x14\x02\x01\x01\x42\x00\xa0\x0d\x30\x0b\x04\x031.2\x01\x01\x00\x01\x01"
+                        [..],
+                    &[second],
+                ]
+                .concat()[..],
+            );
+            match LdapCodec::default().decode(&mut buf) {
+                Ok(Some(Err(malformed))) => assert_eq!(
+                    malformed.err,
+                    LdapProtoError::Unexpected {
+                        structure: "Control",
+                        class: TagClass::Universal,
+                        id: 1,
+                    }
+                ),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ldapserver_codec_saslbind() {
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
+                dn: "".to_string(),
+                cred: LdapBindCred::Sasl {
+                    mechanism: "EXTERNAL".to_string(),
+                    credentials: None,
+                },
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 2,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
+                dn: "".to_string(),
+                cred: LdapBindCred::Sasl {
+                    mechanism: "SCRAM-SHA-256".to_string(),
+                    credentials: Some(vec![0x00, 0xff, 0x6e, 0x2c]),
+                },
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_saslbindresponse() {
+        do_test!(LdapMsg {
+            msgid: 2,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::SaslBindInProgress,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                saslcreds: Some(vec![0x72, 0x3d, 0x00, 0xff]),
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_unbind() {
         do_test!(LdapMsg {
@@ -111,6 +584,38 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_referral() {
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Referral,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![
+                    "ldap://hostb/OU=People,DC=Example,DC=NET".to_string(),
+                    "ldap://hostc/OU=People,DC=Example,DC=NET".to_string(),
+                ],
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 2,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Referral,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec!["ldap://hostb/".to_string()],
+                },
+                name: Some("1.3.6.1.4.1.4203.1.11.3".to_string()),
+                value: Some(Vec::from("hello")),
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_searchrequest() {
         do_test!(LdapMsg {
@@ -124,16 +629,16 @@ mod tests {
                 typesonly: false,
                 filter: LdapFilter::Or(vec![
                     LdapFilter::Present("cn".to_string()),
-                    LdapFilter::Equality("cn".to_string(), "name".to_string()),
+                    LdapFilter::Equality("cn".to_string(), Vec::from("name")),
                     LdapFilter::Not(Box::new(LdapFilter::And(vec![LdapFilter::Present(
                         "cursed".to_string()
                     ),]))),
                     LdapFilter::Substring(
                         "cn".to_string(),
                         LdapSubstringFilter {
-                            initial: Some("abc".to_string()),
-                            any: vec!["def".to_string(), "ghi".to_string()],
-                            final_: Some("jkl".to_string())
+                            initial: Some(Vec::from("abc")),
+                            any: vec![Vec::from("def"), Vec::from("ghi")],
+                            final_: Some(Vec::from("jkl"))
                         }
                     ),
                     LdapFilter::Substring(
@@ -151,6 +656,85 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_searchrequest_ordering() {
+        do_test!(LdapMsg {
+            msgid: 3,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::And(vec![
+                    LdapFilter::GreaterOrEqual("uidNumber".to_string(), Vec::from("1000")),
+                    LdapFilter::LessOrEqual(
+                        "modifyTimestamp".to_string(),
+                        Vec::from("20210101000000Z")
+                    ),
+                ]),
+                attrs: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_searchrequest_approx() {
+        do_test!(LdapMsg {
+            msgid: 3,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::Approx("cn".to_string(), Vec::from("smith")),
+                attrs: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_searchrequest_extensible() {
+        do_test!(LdapMsg {
+            msgid: 3,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::Or(vec![
+                    LdapFilter::Extensible {
+                        rule: Some("1.2.840.113556.1.4.1941".to_string()),
+                        atype: Some("memberOf".to_string()),
+                        value: Vec::from("cn=group,dc=example,dc=com"),
+                        dn_attributes: false,
+                    },
+                    LdapFilter::Extensible {
+                        rule: Some("1.2.840.113556.1.4.803".to_string()),
+                        atype: None,
+                        value: Vec::from("2"),
+                        dn_attributes: false,
+                    },
+                    LdapFilter::Extensible {
+                        rule: None,
+                        atype: Some("ou".to_string()),
+                        value: Vec::from("people"),
+                        dn_attributes: true,
+                    },
+                ]),
+                attrs: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_searchresultentry() {
         do_test!(LdapMsg {
@@ -160,15 +744,15 @@ mod tests {
                 attributes: vec![
                     LdapPartialAttribute {
                         atype: "cn".to_string(),
-                        vals: vec!["demo".to_string(),]
+                        vals: vec![Vec::from("demo"),]
                     },
                     LdapPartialAttribute {
                         atype: "dn".to_string(),
-                        vals: vec!["cn=demo,dc=example,dc=com".to_string(),]
+                        vals: vec![Vec::from("cn=demo,dc=example,dc=com"),]
                     },
                     LdapPartialAttribute {
                         atype: "objectClass".to_string(),
-                        vals: vec!["cursed".to_string(),]
+                        vals: vec![Vec::from("cursed"),]
                     },
                 ]
             }),
@@ -190,6 +774,18 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_searchresultreference() {
+        do_test!(LdapMsg {
+            msgid: 28799790,
+            op: LdapOp::SearchResultReference(vec![
+                "ldap://hostb/OU=People,DC=Example,DC=NET??sub".to_string(),
+                "ldap://hostc/OU=People,DC=Example,DC=NET??sub".to_string(),
+            ]),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_extendedrequest() {
         do_test!(LdapMsg {
@@ -235,6 +831,36 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_intermediateresponse() {
+        do_test!(LdapMsg {
+            msgid: 258,
+            op: LdapOp::IntermediateResponse(LdapIntermediateResponse {
+                name: Some("1.3.6.1.4.1.4203.1.9.1.4".to_string()),
+                value: Some(Vec::from("hello")),
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 258,
+            op: LdapOp::IntermediateResponse(LdapIntermediateResponse {
+                name: None,
+                value: Some(Vec::from("hello")),
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 258,
+            op: LdapOp::IntermediateResponse(LdapIntermediateResponse {
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_addrequest() {
         do_test!(LdapMsg {
@@ -243,13 +869,80 @@ mod tests {
                 dn: "dc=example,dc=com".to_string(),
                 attributes: vec![LdapPartialAttribute {
                     atype: "objectClass".to_string(),
-                    vals: vec!["top".to_string(), "posixAccount".to_string()]
+                    vals: vec![Vec::from("top"), Vec::from("posixAccount")]
                 }],
             }),
             ctrl: vec![],
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_binary_values() {
+        // Not valid utf8, as you would find in jpegPhoto or objectSid.
+        let bin = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10, 0x80, 0xc3, 0x28];
+
+        let attr = LdapPartialAttribute {
+            atype: "jpegPhoto".to_string(),
+            vals: vec![bin.clone(), Vec::from("text")],
+        };
+        assert!(attr.str_vals().is_none());
+
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::AddRequest(LdapAddRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                attributes: vec![attr.clone()],
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 2,
+            op: LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                changes: vec![LdapModify {
+                    operation: LdapModifyType::Replace,
+                    modification: attr.clone(),
+                }],
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 3,
+            op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                attributes: vec![attr.clone()],
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 4,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::And(vec![
+                    LdapFilter::Equality("objectSid".to_string(), bin.clone()),
+                    LdapFilter::Substring(
+                        "jpegPhoto".to_string(),
+                        LdapSubstringFilter {
+                            initial: Some(bin.clone()),
+                            any: vec![bin.clone()],
+                            final_: Some(bin.clone()),
+                        }
+                    ),
+                ]),
+                attrs: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_addresponse() {
         do_test!(LdapMsg {
@@ -264,6 +957,54 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_modifyrequest() {
+        do_test!(LdapMsg {
+            msgid: 233,
+            op: LdapOp::ModifyRequest(LdapModifyRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                changes: vec![
+                    LdapModify {
+                        operation: LdapModifyType::Add,
+                        modification: LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![Vec::from("demo@example.com")]
+                        }
+                    },
+                    LdapModify {
+                        operation: LdapModifyType::Delete,
+                        modification: LdapPartialAttribute {
+                            atype: "description".to_string(),
+                            vals: vec![]
+                        }
+                    },
+                    LdapModify {
+                        operation: LdapModifyType::Replace,
+                        modification: LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![Vec::from("top"), Vec::from("posixAccount")]
+                        }
+                    },
+                ],
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_modifyresponse() {
+        do_test!(LdapMsg {
+            msgid: 23333,
+            op: LdapOp::ModifyResponse(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "dc=exmaple,dc=com".to_string(),
+                message: "msg".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_delrequest() {
         do_test!(LdapMsg {
@@ -287,6 +1028,83 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_ldapserver_codec_modifydnrequest() {
+        do_test!(LdapMsg {
+            msgid: 233,
+            op: LdapOp::ModifyDNRequest(LdapModifyDNRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                newrdn: "cn=renamed".to_string(),
+                deleteoldrdn: true,
+                new_superior: None,
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 234,
+            op: LdapOp::ModifyDNRequest(LdapModifyDNRequest {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                newrdn: "cn=demo".to_string(),
+                deleteoldrdn: false,
+                new_superior: Some("ou=people,dc=example,dc=com".to_string()),
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_modifydnresponse() {
+        do_test!(LdapMsg {
+            msgid: 23333,
+            op: LdapOp::ModifyDNResponse(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "dc=exmaple,dc=com".to_string(),
+                message: "msg".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_comparerequest() {
+        do_test!(LdapMsg {
+            msgid: 233,
+            op: LdapOp::CompareRequest(LdapCompareRequest {
+                dn: "cn=group,dc=example,dc=com".to_string(),
+                atype: "member".to_string(),
+                val: Vec::from("cn=demo,dc=example,dc=com"),
+            }),
+            ctrl: vec![],
+        });
+    }
+
+    #[test]
+    fn test_ldapserver_codec_compareresponse() {
+        do_test!(LdapMsg {
+            msgid: 23333,
+            op: LdapOp::CompareResponse(LdapResult {
+                code: LdapResultCode::CompareTrue,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        });
+
+        do_test!(LdapMsg {
+            msgid: 23334,
+            op: LdapOp::CompareResponse(LdapResult {
+                code: LdapResultCode::CompareFalse,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        });
+    }
+
     #[test]
     fn test_ldapserver_codec_abandonrequest() {
         do_test!(LdapMsg {
@@ -295,4 +1113,541 @@ mod tests {
             ctrl: vec![],
         });
     }
+
+    #[test]
+    fn test_ldapserver_codec_resultcodes() {
+        for code in [
+            LdapResultCode::Canceled,
+            LdapResultCode::AuthorizationDenied,
+            LdapResultCode::ESyncRefreshRequired,
+            LdapResultCode::Unknown(16654),
+        ] {
+            do_test!(LdapMsg {
+                msgid: 1,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: code.clone(),
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            });
+        }
+        assert_eq!(LdapResultCode::from(118), LdapResultCode::Canceled);
+        assert_eq!(
+            LdapResultCode::from(4096),
+            LdapResultCode::ESyncRefreshRequired
+        );
+        assert_eq!(LdapResultCode::from(9), LdapResultCode::Unknown(9));
+        assert_eq!(i64::from(&LdapResultCode::Unknown(9)), 9);
+
+        // A code that has a variant is decoded as it, however it was sent.
+        let mut buf = BytesMut::new();
+        LdapCodec::default()
+            .encode(
+                LdapMsg::new(
+                    1,
+                    LdapOp::BindResponse(LdapBindResponse {
+                        res: LdapResult {
+                            code: LdapResultCode::Unknown(49),
+                            matcheddn: "".to_string(),
+                            message: "".to_string(),
+                            referral: vec![],
+                        },
+                        saslcreds: None,
+                    }),
+                ),
+                &mut buf,
+            )
+            .expect("failed to encode");
+        match LdapCodec::default().decode(&mut buf) {
+            Ok(Some(Ok(LdapMsg {
+                op: LdapOp::BindResponse(LdapBindResponse { res, .. }),
+                ..
+            }))) => assert!(matches!(res.code, LdapResultCode::InvalidCredentials)),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_ldapserver_codec_partial() {
+        let msg = LdapMsg::new(
+            1,
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=demo,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec![Vec::from("demo")],
+                }],
+            }),
+        );
+        let mut encoded = BytesMut::new();
+        LdapCodec::default()
+            .encode(msg.clone(), &mut encoded)
+            .expect("failed to encode");
+        // Followed by the start of the next message.
+        encoded.extend_from_slice(b"\x30\x84");
+
+        // Feed it in a byte at a time, as a slow client might.
+        let mut codec = LdapCodec::default();
+        let mut buf = BytesMut::new();
+        let (whole, next) = encoded.split_at(encoded.len() - 2);
+        for b in whole[..whole.len() - 1].iter() {
+            buf.extend_from_slice(&[*b]);
+            assert_eq!(codec.decode(&mut buf).expect("failed to decode"), None);
+        }
+        buf.extend_from_slice(&whole[whole.len() - 1..]);
+        buf.extend_from_slice(next);
+        assert_eq!(
+            codec.decode(&mut buf).expect("failed to decode"),
+            Some(Ok(msg))
+        );
+        assert_eq!(&buf[..], b"\x30\x84");
+        assert_eq!(codec.decode(&mut buf).expect("failed to decode"), None);
+    }
+
+    #[test]
+    fn test_ldapserver_codec_partial_reserve() {
+        // A header that claims a 1MiB message, with nothing behind it, must
+        // not make us allocate the lot.
+        let mut buf = BytesMut::from(&b"\x30\x83\x10\x00\x00"[..]);
+        assert_eq!(
+            LdapCodec::default()
+                .decode(&mut buf)
+                .expect("failed to decode"),
+            None
+        );
+        assert!(buf.capacity() < 0x100000);
+    }
+
+    #[test]
+    fn test_ldapserver_codec_limits() {
+        let limits = LdapLimits {
+            max_pdu_size: 128,
+            max_filter_depth: 3,
+            max_attributes: 2,
+            max_values_per_attribute: 2,
+        };
+        let check = |msg: LdapMsg| {
+            let mut buf = BytesMut::new();
+            LdapCodec::default()
+                .encode(msg, &mut buf)
+                .expect("failed to encode");
+            LdapCodec::with_limits(limits.clone()).decode(&mut buf)
+        };
+        let search = |filter: LdapFilter| {
+            LdapMsg::new(
+                1,
+                LdapOp::SearchRequest(LdapSearchRequest {
+                    base: "".to_string(),
+                    scope: LdapSearchScope::Base,
+                    aliases: LdapDerefAliases::Never,
+                    sizelimit: 0,
+                    timelimit: 0,
+                    typesonly: false,
+                    filter,
+                    attrs: vec![],
+                }),
+            )
+        };
+        let not = |depth: usize| {
+            (1..depth).fold(LdapFilter::Present("cn".to_string()), |f, _| {
+                LdapFilter::Not(Box::new(f))
+            })
+        };
+
+        // A length far beyond the limit is rejected from the header alone.
+        let mut buf = BytesMut::from(&b"\x30\x84\x7f\xff\xff\xff"[..]);
+        match LdapCodec::default().decode(&mut buf) {
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded { limit, .. })) => {
+                assert_eq!(limit, 4 * 1024 * 1024)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        assert!(matches!(check(search(not(3))), Ok(Some(Ok(_)))));
+        match check(search(not(4))) {
+            Err(LdapCodecError::Proto(e)) => {
+                assert_eq!(e.to_string(), "Filter exceeds limit (3)")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        // The filter is found in the raw bytes, so it is reported as such
+        // however deep it goes.
+        assert!(matches!(
+            check(search(not(20))),
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                structure: "Filter",
+                ..
+            }))
+        ));
+        // Raw pdus, for the checks that must happen before parsing.
+        let tlv = |tag: u8, contents: &[u8]| [&[tag, contents.len() as u8][..], contents].concat();
+        let decode_raw = |pdu: Vec<u8>| {
+            let mut buf = BytesMut::from(&pdu[..]);
+            LdapCodec::with_limits(limits.clone()).decode(&mut buf)
+        };
+
+        // Anything else that nests too deeply is rejected as a whole.
+        let nested = (0..8).fold(Vec::new(), |inner, _| tlv(0x30, &inner));
+        let bind = tlv(0x30, &[&tlv(0x02, &[1])[..], &tlv(0x60, &nested)].concat());
+        assert!(matches!(
+            decode_raw(bind),
+            Err(LdapCodecError::Proto(LdapProtoError::LimitExceeded {
+                structure: "LDAPMessage",
+                ..
+            }))
+        ));
+
+        // Values are counted from the raw bytes, so the limit is hit before
+        // the parser would have seen that the last value is not valid BER.
+        let vals = [&[0x04, 0x00, 0x04, 0x00, 0x04, 0x00][..], &[0x1f, 0x00]].concat();
+        let attr = [&tlv(0x04, b"cn")[..], &tlv(0x31, &vals)].concat();
+        let entry = [&tlv(0x04, b"")[..], &tlv(0x30, &tlv(0x30, &attr))].concat();
+        let msg = tlv(0x30, &[&tlv(0x02, &[1])[..], &tlv(0x64, &entry)].concat());
+        match decode_raw(msg) {
+            Err(LdapCodecError::Proto(e)) => {
+                assert_eq!(e.to_string(), "PartialAttribute.vals exceeds limit (2)")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let add = |vals: usize| {
+            LdapMsg::new(
+                1,
+                LdapOp::AddRequest(LdapAddRequest {
+                    dn: "dc=example,dc=com".to_string(),
+                    attributes: vec![LdapAttribute {
+                        atype: "dc".to_string(),
+                        vals: (0..vals).map(|i| vec![i as u8]).collect(),
+                    }],
+                }),
+            )
+        };
+        assert!(matches!(check(add(2)), Ok(Some(Ok(_)))));
+        match check(add(3)) {
+            Err(LdapCodecError::Proto(e)) => {
+                assert_eq!(e.to_string(), "PartialAttribute.vals exceeds limit (2)")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let modify = |changes: usize, vals: usize| {
+            LdapMsg::new(
+                1,
+                LdapOp::ModifyRequest(LdapModifyRequest {
+                    dn: "dc=example,dc=com".to_string(),
+                    changes: (0..changes)
+                        .map(|_| LdapModify {
+                            operation: LdapModifyType::Replace,
+                            modification: LdapPartialAttribute {
+                                atype: "dc".to_string(),
+                                vals: (0..vals).map(|i| vec![i as u8]).collect(),
+                            },
+                        })
+                        .collect(),
+                }),
+            )
+        };
+        assert!(matches!(check(modify(2, 2)), Ok(Some(Ok(_)))));
+        match check(modify(3, 1)) {
+            Err(LdapCodecError::Proto(e)) => {
+                assert_eq!(e.to_string(), "ModifyRequest.changes exceeds limit (2)")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        match check(modify(1, 3)) {
+            Err(LdapCodecError::Proto(e)) => {
+                assert_eq!(e.to_string(), "PartialAttribute.vals exceeds limit (2)")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A limit of usize::MAX means no limit, rather than overflowing.
+        let unlimited = LdapLimits {
+            max_filter_depth: usize::MAX,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        LdapCodec::default()
+            .encode(search(not(20)), &mut buf)
+            .expect("failed to encode");
+        assert!(matches!(
+            LdapCodec::with_limits(unlimited).decode(&mut buf),
+            Ok(Some(Ok(_)))
+        ));
+    }
+
+    #[test]
+    fn test_ldapserver_codec_pagedresults() {
+        let paged = LdapPagedResults {
+            size: 10,
+            cookie: vec![],
+        };
+        let ctrl = LdapControl::from(paged.clone());
+        assert_eq!(
+            ctrl.value,
+            Some(vec![0x30, 0x05, 0x02, 0x01, 0x0a, 0x04, 0x00])
+        );
+        assert_eq!(LdapPagedResults::try_from(&ctrl), Ok(paged));
+
+        do_test!(LdapMsg {
+            msgid: 2,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![LdapPagedResults {
+                size: 1000,
+                cookie: vec![0, 0, 0, 0, 0, 0, 0x01, 0xf4],
+            }
+            .into()],
+        });
+
+        // A negative size is out of range.
+        let ctrl = LdapControl {
+            oid: LdapPagedResults::OID.to_string(),
+            criticality: true,
+            value: Some(vec![0x30, 0x05, 0x02, 0x01, 0xff, 0x04, 0x00]),
+        };
+        assert_eq!(
+            LdapPagedResults::try_from(&ctrl),
+            Err(LdapProtoError::OutOfRange {
+                structure: "realSearchControlValue",
+                field: "size",
+                value: -1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_ldapserver_codec_bindversion() {
+        // Older versions are kept for the server to refuse.
+        do_test!(LdapMsg {
+            msgid: 1,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version: 2,
+                dn: "cn=demo".to_string(),
+                cred: LdapBindCred::Simple("password".to_string()),
+            }),
+            ctrl: vec![],
+        });
+
+        // But anything outside of 1 .. 127 is malformed.
+        let mut buf = BytesMut::new();
+        let mut codec = LdapCodec::default();
+        codec
+            .encode(
+                LdapMsg {
+                    msgid: 1,
+                    op: LdapOp::BindRequest(LdapBindRequest {
+                        version: 128,
+                        dn: "".to_string(),
+                        cred: LdapBindCred::Simple("".to_string()),
+                    }),
+                    ctrl: vec![],
+                },
+                &mut buf,
+            )
+            .expect("failed to encode");
+        match codec.decode(&mut buf) {
+            Ok(Some(Err(malformed))) => {
+                assert_eq!(
+                    malformed.err,
+                    LdapProtoError::OutOfRange {
+                        structure: "BindRequest",
+                        field: "version",
+                        value: 128,
+                    }
+                );
+                match malformed.gen_protocol_error().map(|m| m.op) {
+                    Some(LdapOp::BindResponse(br)) => {
+                        assert_eq!(br.res.code, LdapResultCode::ProtocolError)
+                    }
+                    op => panic!("unexpected op {:?}", op),
+                }
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_ldapserver_codec_strict() {
+        let decode = |strict, bytes: &[u8]| {
+            LdapCodec::default()
+                .strict(strict)
+                .decode(&mut BytesMut::from(bytes))
+        };
+
+        // A search with typesOnly sent as 0x01.
+        let search = b"\x30\x25\x02\x01\x01\x63\x20\x04\x00\x0a\x01\x00\x0a\x01\x00\x02\x01\x00\
+                       \x02\x01\x00\x01\x01\x01\x87\x0bobjectClass\x30\x00";
+        match decode(false, search) {
+            Ok(Some(Ok(LdapMsg {
+                op: LdapOp::SearchRequest(sr),
+                ..
+            }))) => assert!(sr.typesonly),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match decode(true, search) {
+            Ok(Some(Err(malformed))) => {
+                assert_eq!(malformed.msgid, 1);
+                assert_eq!(
+                    malformed.err,
+                    LdapProtoError::NonCanonical {
+                        structure: "SearchRequest",
+                        field: "typesOnly",
+                    }
+                );
+                assert_eq!(
+                    malformed.err.to_string(),
+                    "SearchRequest.typesOnly not canonical"
+                );
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // (cn=ab*cd*ef) with the substrings sent as universal tags.
+        let search = b"\x30\x2c\x02\x01\x01\x63\x27\x04\x00\x0a\x01\x00\x0a\x01\x00\x02\x01\x00\
+                       \x02\x01\x00\x01\x01\x00\xa4\x12\x04\x02cn\x30\x0c\x00\x02ab\x01\x02cd\
+                       \x02\x02ef\x30\x00";
+        assert!(matches!(decode(false, search), Ok(Some(Ok(_)))));
+        match decode(true, search) {
+            Ok(Some(Err(malformed))) => assert_eq!(
+                malformed.err,
+                LdapProtoError::Unexpected {
+                    structure: "SubstringFilter",
+                    class: TagClass::Universal,
+                    id: 0,
+                }
+            ),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // An unbind with msgid -1, which is now sign extended.
+        let unbind = b"\x30\x05\x02\x01\xff\x42\x00";
+        match decode(false, unbind) {
+            Ok(Some(Ok(msg))) => assert_eq!(msg.msgid, -1),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match decode(true, unbind) {
+            Err(LdapCodecError::Proto(LdapProtoError::OutOfRange {
+                structure: "LDAPMessage",
+                field: "messageID",
+                value: -1,
+            })) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // And one with msgid 2^31.
+        match decode(true, b"\x30\x09\x02\x05\x00\x80\x00\x00\x00\x42\x00") {
+            Err(LdapCodecError::Proto(LdapProtoError::OutOfRange {
+                value: 2147483648, ..
+            })) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // An indefinite length.
+        match decode(true, b"\x30\x80\x02\x01\x01\x42\x00\x00\x00") {
+            Err(LdapCodecError::IndefiniteLength) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // An abandon followed by something that isn't controls.
+        let abandon = b"\x30\x09\x02\x01\x01\x50\x01\x01\x04\x01\x78";
+        match decode(false, abandon) {
+            Ok(Some(Ok(msg))) => assert_eq!(msg.op, LdapOp::AbandonRequest(1)),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match decode(true, abandon) {
+            Ok(Some(Err(malformed))) => assert_eq!(
+                malformed.err,
+                LdapProtoError::Unexpected {
+                    structure: "LDAPMessage",
+                    class: TagClass::Universal,
+                    id: 4,
+                }
+            ),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A delete response with two extra strings after the result.
+        match decode(
+            true,
+            b"\x30\x12\x02\x01\x01\x6b\x0d\x0a\x01\x00\x04\x00\x04\x00\x04\x01x\x04\x01y",
+        ) {
+            Ok(Some(Err(malformed))) => {
+                assert_eq!(malformed.op_id, 11);
+                assert_eq!(
+                    malformed.err,
+                    LdapProtoError::Unexpected {
+                        structure: "LDAPResult",
+                        class: TagClass::Universal,
+                        id: 4,
+                    }
+                );
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_ldapserver_codec_decode_error() {
+        // A search request with scope 3, which doesn't exist, followed by
+        // a valid abandon.
+        let mut buf = BytesMut::from(
+            &b"\x30\x25\x02\x01\x01\x63\x20\x04\x00\x0a\x01\x03\x0a\x01\x00\x02\x01\x00\
+               \x02\x01\x00\x01\x01\x00\x87\x0bobjectClass\x30\x00\
+               \x30\x06\x02\x01\x02\x50\x01\x01"[..],
+        );
+        match LdapCodec::default().decode(&mut buf) {
+            Ok(Some(Err(malformed))) => {
+                assert_eq!(malformed.msgid, 1);
+                assert_eq!(
+                    malformed.err,
+                    LdapProtoError::OutOfRange {
+                        structure: "SearchRequest",
+                        field: "scope",
+                        value: 3,
+                    }
+                );
+                assert_eq!(
+                    malformed.err.to_string(),
+                    "SearchRequest.scope out of range (3)"
+                );
+                let rmsg = malformed.gen_protocol_error().expect("no response");
+                assert_eq!(rmsg.msgid, 1);
+                match rmsg.op {
+                    LdapOp::SearchResultDone(res) => {
+                        assert_eq!(res.code, LdapResultCode::ProtocolError)
+                    }
+                    op => panic!("unexpected op {:?}", op),
+                }
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        // The stream is still usable.
+        assert_eq!(
+            LdapCodec::default()
+                .decode(&mut buf)
+                .expect("failed to decode"),
+            Some(Ok(LdapMsg {
+                msgid: 2,
+                op: LdapOp::AbandonRequest(1),
+                ctrl: vec![],
+            }))
+        );
+
+        // An application tag that isn't an operation.
+        let mut buf = BytesMut::from(&b"\x30\x05\x02\x01\x01\x5e\x00"[..]);
+        match LdapCodec::default().decode(&mut buf) {
+            Err(LdapCodecError::Proto(LdapProtoError::UnknownOp { class, id })) => {
+                assert_eq!(class, TagClass::Application);
+                assert_eq!(id, 30);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
 }
@@ -1,70 +1,485 @@
+use crate::encode::BerEncode;
+use bytes::BytesMut;
 use lber::common::TagClass;
+use lber::parse::parse_tag;
 use lber::structure::{StructureTag, PL};
 use lber::structures::ASNTag;
 use lber::structures::{
     Boolean, Enumerated, ExplicitTag, Integer, Null, OctetString, Sequence, Set, Tag,
 };
 use lber::universal::Types;
+use lber::IResult;
 use std::convert::{From, TryFrom};
+use std::fmt;
 use std::iter::once_with;
 
+/// Why a message could not be decoded. Structures and fields are named as
+/// they are in the ASN.1 of https://tools.ietf.org/html/rfc4511 so that a
+/// rejected packet can be traced back to the part of the spec it broke.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapProtoError {
+    /// A required field was absent.
+    Missing {
+        structure: &'static str,
+        field: &'static str,
+    },
+    /// A field was present, but had the wrong tag or could not be decoded.
+    /// The field is empty when the structure itself was malformed.
+    Invalid {
+        structure: &'static str,
+        field: &'static str,
+        class: TagClass,
+        id: u64,
+    },
+    /// A field decoded, but its value is not one the RFC allows.
+    OutOfRange {
+        structure: &'static str,
+        field: &'static str,
+        value: i64,
+    },
+    /// An element was present that the structure does not permit.
+    Unexpected {
+        structure: &'static str,
+        class: TagClass,
+        id: u64,
+    },
+    /// The protocolOp is not an operation we know of.
+    UnknownOp { class: TagClass, id: u64 },
+    /// A field was larger, had more elements or nested more deeply than the
+    /// decoder's limits allow.
+    LimitExceeded {
+        structure: &'static str,
+        field: &'static str,
+        limit: usize,
+    },
+    /// A field was not in its canonical DER form, such as a boolean that was
+    /// neither 0x00 or 0xff. This is only checked by a strict codec.
+    NonCanonical {
+        structure: &'static str,
+        field: &'static str,
+    },
+}
+
+impl fmt::Display for LdapProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LdapProtoError::Missing { structure, field } => {
+                write!(f, "{}.{} missing", structure, field)
+            }
+            LdapProtoError::Invalid {
+                structure,
+                field: "",
+                class,
+                id,
+            } => write!(f, "{} invalid (tag {:?} {})", structure, class, id),
+            LdapProtoError::Invalid {
+                structure,
+                field,
+                class,
+                id,
+            } => write!(
+                f,
+                "{}.{} invalid (tag {:?} {})",
+                structure, field, class, id
+            ),
+            LdapProtoError::OutOfRange {
+                structure,
+                field,
+                value,
+            } => write!(f, "{}.{} out of range ({})", structure, field, value),
+            LdapProtoError::Unexpected {
+                structure,
+                class,
+                id,
+            } => write!(
+                f,
+                "{} unexpected element (tag {:?} {})",
+                structure, class, id
+            ),
+            LdapProtoError::UnknownOp { class, id } => {
+                write!(f, "unknown protocolOp (tag {:?} {})", class, id)
+            }
+            LdapProtoError::LimitExceeded {
+                structure,
+                field: "",
+                limit,
+            } => write!(f, "{} exceeds limit ({})", structure, limit),
+            LdapProtoError::LimitExceeded {
+                structure,
+                field,
+                limit,
+            } => write!(f, "{}.{} exceeds limit ({})", structure, field, limit),
+            LdapProtoError::NonCanonical { structure, field } => {
+                write!(f, "{}.{} not canonical", structure, field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LdapProtoError {}
+
+/// Decode a single field with `f`, reporting which field failed and the tag it
+/// had if it was absent or `f` rejected it.
+fn decode_field<T, F>(
+    tag: Option<StructureTag>,
+    structure: &'static str,
+    field: &'static str,
+    f: F,
+) -> Result<T, LdapProtoError>
+where
+    F: FnOnce(StructureTag) -> Option<T>,
+{
+    let tag = tag.ok_or(LdapProtoError::Missing { structure, field })?;
+    let (class, id) = (tag.class, tag.id);
+    f(tag).ok_or(LdapProtoError::Invalid {
+        structure,
+        field,
+        class,
+        id,
+    })
+}
+
+// The elements of a constructed tag, if there is one.
+fn children(tag: Option<&StructureTag>) -> &[StructureTag] {
+    match tag.map(|t| &t.payload) {
+        Some(PL::C(inner)) => inner,
+        _ => &[],
+    }
+}
+
+/// Fail if a client sent more than `limit` of something.
+pub(crate) fn check_count(
+    count: usize,
+    structure: &'static str,
+    field: &'static str,
+    limit: usize,
+) -> Result<(), LdapProtoError> {
+    if count > limit {
+        Err(LdapProtoError::LimitExceeded {
+            structure,
+            field,
+            limit,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// The checks of a strict codec, for the things the decoders accept loosely:
+/// a msgid outside of 0 .. maxInt, booleans other than 0x00 and 0xff, and
+/// elements after the last field a structure has. Anything the decoders
+/// reject outright is left for them to report.
+///
+/// As with decoding, a bad msgid can't be answered, but anything after it
+/// is returned as a malformed message.
+pub(crate) fn check_strict(
+    msg: &StructureTag,
+) -> Result<Result<(), LdapMalformedMsg>, LdapProtoError> {
+    let seq = children(Some(msg));
+    let msgid = match seq.first().map(|t| &t.payload) {
+        Some(PL::P(bv)) => match ber_integer_to_i64(bv) {
+            Some(v) if v < 0 || v > i32::MAX as i64 => {
+                return Err(LdapProtoError::OutOfRange {
+                    structure: "LDAPMessage",
+                    field: "messageID",
+                    value: v,
+                })
+            }
+            Some(v) => v as i32,
+            None => return Ok(Ok(())),
+        },
+        _ => return Ok(Ok(())),
+    };
+    let op = match seq.get(1) {
+        Some(op) => op,
+        None => return Ok(Ok(())),
+    };
+
+    let res = match seq.get(2) {
+        Some(t) if t.class != TagClass::Context || t.id != 0 => Err(LdapProtoError::Unexpected {
+            structure: "LDAPMessage",
+            class: t.class,
+            id: t.id,
+        }),
+        ctrl => check_strict_op(op).and_then(|_| {
+            children(ctrl).iter().try_for_each(|c| {
+                let inner = children(Some(c));
+                check_fields(inner, "Control", 3)?;
+                check_bool(
+                    inner.get(1),
+                    TagClass::Universal,
+                    Types::Boolean as u64,
+                    "Control",
+                    "criticality",
+                )
+            })
+        }),
+    };
+    Ok(res.map_err(|err| LdapMalformedMsg {
+        msgid,
+        op_id: op.id,
+        err,
+    }))
+}
+
+// No more than max elements.
+fn check_fields(
+    tags: &[StructureTag],
+    structure: &'static str,
+    max: usize,
+) -> Result<(), LdapProtoError> {
+    match tags.get(max) {
+        Some(t) => Err(LdapProtoError::Unexpected {
+            structure,
+            class: t.class,
+            id: t.id,
+        }),
+        None => Ok(()),
+    }
+}
+
+// If the tag is the boolean we're looking for, it must be 0x00 or 0xff.
+fn check_bool(
+    tag: Option<&StructureTag>,
+    class: TagClass,
+    id: u64,
+    structure: &'static str,
+    field: &'static str,
+) -> Result<(), LdapProtoError> {
+    match tag {
+        Some(StructureTag {
+            class: c,
+            id: i,
+            payload: PL::P(bv),
+        }) if *c == class && *i == id && bv[..] != [0x00] && bv[..] != [0xff] => {
+            Err(LdapProtoError::NonCanonical { structure, field })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_strict_op(op: &StructureTag) -> Result<(), LdapProtoError> {
+    let inner = children(Some(op));
+    let check_attr = |a: &StructureTag| check_fields(children(Some(a)), "PartialAttribute", 2);
+    if op.class != TagClass::Application {
+        return Ok(());
+    }
+
+    match op.id {
+        0 => {
+            check_fields(inner, "BindRequest", 3)?;
+            match inner.get(2) {
+                Some(t) if t.class == TagClass::Context && t.id == 3 => {
+                    check_fields(children(Some(t)), "SaslCredentials", 2)
+                }
+                _ => Ok(()),
+            }
+        }
+        // LDAPResult, then the optional referral and serverSaslCreds.
+        1 => check_fields(inner, "BindResponse", 5),
+        3 => {
+            check_fields(inner, "SearchRequest", 8)?;
+            check_bool(
+                inner.get(5),
+                TagClass::Universal,
+                Types::Boolean as u64,
+                "SearchRequest",
+                "typesOnly",
+            )?;
+            // The filter depth has already been limited, but there's no need
+            // to recurse here either.
+            let mut stack: Vec<&StructureTag> = inner.get(6).into_iter().collect();
+            while let Some(f) = stack.pop() {
+                if f.class != TagClass::Context {
+                    continue;
+                }
+                let fi = children(Some(f));
+                match f.id {
+                    0 | 1 => stack.extend(fi),
+                    2 => {
+                        check_fields(fi, "Filter", 1)?;
+                        stack.extend(fi);
+                    }
+                    3 | 5 | 6 | 8 => check_fields(fi, "AttributeValueAssertion", 2)?,
+                    4 => {
+                        check_fields(fi, "SubstringFilter", 2)?;
+                        // The choices are context tags, not the universal
+                        // ones this crate used to send.
+                        if let Some(t) = children(fi.get(1))
+                            .iter()
+                            .find(|t| t.class != TagClass::Context)
+                        {
+                            return Err(LdapProtoError::Unexpected {
+                                structure: "SubstringFilter",
+                                class: t.class,
+                                id: t.id,
+                            });
+                        }
+                    }
+                    9 => {
+                        check_fields(fi, "MatchingRuleAssertion", 4)?;
+                        check_bool(
+                            fi.last(),
+                            TagClass::Context,
+                            4,
+                            "MatchingRuleAssertion",
+                            "dnAttributes",
+                        )?;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+        4 | 8 => {
+            let structure = if op.id == 4 {
+                "SearchResultEntry"
+            } else {
+                "AddRequest"
+            };
+            check_fields(inner, structure, 2)?;
+            children(inner.get(1)).iter().try_for_each(check_attr)
+        }
+        5 | 7 | 9 | 11 | 13 | 15 => check_fields(inner, "LDAPResult", 4),
+        6 => {
+            check_fields(inner, "ModifyRequest", 2)?;
+            children(inner.get(1)).iter().try_for_each(|c| {
+                let change = children(Some(c));
+                check_fields(change, "ModifyRequest", 2)?;
+                change.get(1).map_or(Ok(()), check_attr)
+            })
+        }
+        12 => {
+            check_fields(inner, "ModifyDNRequest", 4)?;
+            check_bool(
+                inner.get(2),
+                TagClass::Universal,
+                Types::Boolean as u64,
+                "ModifyDNRequest",
+                "deleteoldrdn",
+            )
+        }
+        14 => {
+            check_fields(inner, "CompareRequest", 2)?;
+            check_fields(children(inner.get(1)), "AttributeValueAssertion", 2)
+        }
+        23 => check_fields(inner, "ExtendedRequest", 2),
+        // LDAPResult, then the optional responseName and responseValue.
+        24 => check_fields(inner, "ExtendedResponse", 6),
+        25 => check_fields(inner, "IntermediateResponse", 2),
+        _ => Ok(()),
+    }
+}
+
+fn decode_string(tag: StructureTag) -> Option<String> {
+    tag.match_class(TagClass::Universal)
+        .and_then(|t| t.match_id(Types::OctetString as u64))
+        .and_then(|t| t.expect_primitive())
+        .and_then(|bv| String::from_utf8(bv).ok())
+}
+
+fn decode_octets(tag: StructureTag) -> Option<Vec<u8>> {
+    tag.match_class(TagClass::Universal)
+        .and_then(|t| t.match_id(Types::OctetString as u64))
+        .and_then(|t| t.expect_primitive())
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapMsg {
     pub msgid: i32,
     pub op: LdapOp,
-    pub ctrl: Vec<()>,
+    pub ctrl: Vec<LdapControl>,
+}
+
+/// A message whose envelope decoded, but whose protocolOp or controls did not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapMalformedMsg {
+    pub msgid: i32,
+    /// The application tag of the protocolOp, so the right kind of response
+    /// can be sent.
+    pub op_id: u64,
+    pub err: LdapProtoError,
+}
+
+// https://tools.ietf.org/html/rfc4511#section-4.1.11
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapControl {
+    pub oid: String,
+    pub criticality: bool,
+    pub value: Option<Vec<u8>>,
+}
+
+/// The value of the simple paged results control.
+/// https://tools.ietf.org/html/rfc2696
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapPagedResults {
+    /// In a request, how many entries the client wants per page. In a
+    /// response, the server's estimate of how many there are in total, or 0
+    /// if it doesn't know.
+    pub size: i32,
+    /// Empty in the first request and the last response, otherwise whatever
+    /// the server sent back with the previous page.
+    pub cookie: Vec<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-#[repr(i64)]
 pub enum LdapResultCode {
-    Success = 0,
-    OperationsError = 1,
-    ProtocolError = 2,
-    TimeLimitExceeded = 3,
-    SizeLimitExceeded = 4,
-    CompareFalse = 5,
-    CompareTrue = 6,
-    AuthMethodNotSupported = 7,
-    StrongerAuthRequired = 8,
-    // 9 reserved?
-    Referral = 10,
-    AdminLimitExceeded = 11,
-    UnavailableCriticalExtension = 12,
-    ConfidentialityRequired = 13,
-    SaslBindInProgress = 14,
-    // 15 ?
-    NoSuchAttribute = 16,
-    UndefinedAttributeType = 17,
-    InappropriateMatching = 18,
-    ConstraintViolation = 19,
-    AttributeOrValueExists = 20,
-    InvalidAttributeSyntax = 21,
-    //22 31
-    NoSuchObject = 32,
-    AliasProblem = 33,
-    InvalidDNSyntax = 34,
-    // 35
-    AliasDereferencingProblem = 36,
-    // 37 - 47
-    InappropriateAuthentication = 48,
-    InvalidCredentials = 49,
-    InsufficentAccessRights = 50,
-    Busy = 51,
-    Unavailable = 52,
-    UnwillingToPerform = 53,
-    LoopDetect = 54,
-    // 55 - 63
-    NamingViolation = 64,
-    ObjectClassViolation = 65,
-    NotAllowedOnNonLeaf = 66,
-    NotALlowedOnRDN = 67,
-    EntryAlreadyExists = 68,
-    ObjectClassModsProhibited = 69,
-    // 70
-    AffectsMultipleDSAs = 71,
-    // 72 - 79
-    Other = 80,
+    Success,
+    OperationsError,
+    ProtocolError,
+    TimeLimitExceeded,
+    SizeLimitExceeded,
+    CompareFalse,
+    CompareTrue,
+    AuthMethodNotSupported,
+    StrongerAuthRequired,
+    Referral,
+    AdminLimitExceeded,
+    UnavailableCriticalExtension,
+    ConfidentialityRequired,
+    SaslBindInProgress,
+    NoSuchAttribute,
+    UndefinedAttributeType,
+    InappropriateMatching,
+    ConstraintViolation,
+    AttributeOrValueExists,
+    InvalidAttributeSyntax,
+    NoSuchObject,
+    AliasProblem,
+    InvalidDNSyntax,
+    AliasDereferencingProblem,
+    InappropriateAuthentication,
+    InvalidCredentials,
+    InsufficentAccessRights,
+    Busy,
+    Unavailable,
+    UnwillingToPerform,
+    LoopDetect,
+    NamingViolation,
+    ObjectClassViolation,
+    NotAllowedOnNonLeaf,
+    NotALlowedOnRDN,
+    EntryAlreadyExists,
+    ObjectClassModsProhibited,
+    AffectsMultipleDSAs,
+    Other,
+    // https://tools.ietf.org/html/rfc3909
+    Canceled,
+    NoSuchOperation,
+    TooLate,
+    CannotCancel,
+    // https://tools.ietf.org/html/rfc4528
+    AssertionFailed,
+    // https://tools.ietf.org/html/rfc4370
+    AuthorizationDenied,
+    // https://tools.ietf.org/html/rfc4533
+    ESyncRefreshRequired,
+    /// A code we don't know of. This allows responses from newer or more
+    /// exotic servers to still be decoded. Decoding and `From<i64>` only
+    /// produce this for codes without a variant of their own, so don't build
+    /// one for a code that has one - it would be sent as that code, but would
+    /// not be equal to, or match, the named variant.
+    Unknown(i64),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -72,7 +487,7 @@ pub struct LdapResult {
     pub code: LdapResultCode,
     pub matcheddn: String,
     pub message: String,
-    pub referral: Vec<()>,
+    pub referral: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -84,6 +499,7 @@ pub enum LdapOp {
     SearchRequest(LdapSearchRequest),
     SearchResultEntry(LdapSearchResultEntry),
     SearchResultDone(LdapResult),
+    SearchResultReference(Vec<String>),
     // https://datatracker.ietf.org/doc/html/rfc4511#section-4.6
     ModifyRequest(LdapModifyRequest),
     ModifyResponse(LdapResult),
@@ -93,20 +509,34 @@ pub enum LdapOp {
     // https://tools.ietf.org/html/rfc4511#section-4.8
     DelRequest(String),
     DelResponse(LdapResult),
+    // https://tools.ietf.org/html/rfc4511#section-4.9
+    ModifyDNRequest(LdapModifyDNRequest),
+    ModifyDNResponse(LdapResult),
+    // https://tools.ietf.org/html/rfc4511#section-4.10
+    CompareRequest(LdapCompareRequest),
+    CompareResponse(LdapResult),
     // https://tools.ietf.org/html/rfc4511#section-4.11
     AbandonRequest(i32),
     // https://tools.ietf.org/html/rfc4511#section-4.12
     ExtendedRequest(LdapExtendedRequest),
     ExtendedResponse(LdapExtendedResponse),
+    // https://tools.ietf.org/html/rfc4511#section-4.13
+    IntermediateResponse(LdapIntermediateResponse),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum LdapBindCred {
-    Simple(String), // Sasl
+    Simple(String),
+    Sasl {
+        mechanism: String,
+        credentials: Option<Vec<u8>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapBindRequest {
+    /// The protocol version, which is 3 for any client we can talk to.
+    pub version: i32,
     pub dn: String,
     pub cred: LdapBindCred,
 }
@@ -114,7 +544,8 @@ pub struct LdapBindRequest {
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapBindResponse {
     pub res: LdapResult,
-    pub saslcreds: Option<String>,
+    // 7
+    pub saslcreds: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -136,9 +567,9 @@ pub enum LdapDerefAliases {
 
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct LdapSubstringFilter {
-    pub initial: Option<String>,
-    pub any: Vec<String>,
-    pub final_: Option<String>,
+    pub initial: Option<Vec<u8>>,
+    pub any: Vec<Vec<u8>>,
+    pub final_: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -146,13 +577,18 @@ pub enum LdapFilter {
     And(Vec<LdapFilter>),
     Or(Vec<LdapFilter>),
     Not(Box<LdapFilter>),
-    Equality(String, String),
+    Equality(String, Vec<u8>),
     Substring(String, LdapSubstringFilter),
-    //GE
-    //LE
+    GreaterOrEqual(String, Vec<u8>),
+    LessOrEqual(String, Vec<u8>),
     Present(String),
-    //Approx
-    //Extensible
+    Approx(String, Vec<u8>),
+    Extensible {
+        rule: Option<String>,
+        atype: Option<String>,
+        value: Vec<u8>,
+        dn_attributes: bool,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -171,7 +607,7 @@ pub struct LdapSearchRequest {
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapPartialAttribute {
     pub atype: String,
-    pub vals: Vec<String>,
+    pub vals: Vec<Vec<u8>>,
 }
 
 // A PartialAttribute allows zero values, while
@@ -210,6 +646,22 @@ pub enum LdapModifyType {
     Replace = 2,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapModifyDNRequest {
+    pub dn: String,
+    pub newrdn: String,
+    pub deleteoldrdn: bool,
+    // 0
+    pub new_superior: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapCompareRequest {
+    pub dn: String,
+    pub atype: String,
+    pub val: Vec<u8>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapExtendedRequest {
     // 0
@@ -227,6 +679,14 @@ pub struct LdapExtendedResponse {
     pub value: Option<Vec<u8>>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapIntermediateResponse {
+    // 0
+    pub name: Option<String>,
+    // 1
+    pub value: Option<Vec<u8>>,
+}
+
 impl From<LdapBindCred> for Tag {
     fn from(value: LdapBindCred) -> Tag {
         match value {
@@ -235,6 +695,29 @@ impl From<LdapBindCred> for Tag {
                 class: TagClass::Context,
                 inner: Vec::from(pw),
             }),
+            LdapBindCred::Sasl {
+                mechanism,
+                credentials,
+            } => Tag::Sequence(Sequence {
+                id: 3,
+                class: TagClass::Context,
+                inner: once_with(|| {
+                    Some(Tag::OctetString(OctetString {
+                        inner: Vec::from(mechanism),
+                        ..Default::default()
+                    }))
+                })
+                .chain(once_with(|| {
+                    credentials.map(|c| {
+                        Tag::OctetString(OctetString {
+                            inner: c,
+                            ..Default::default()
+                        })
+                    })
+                }))
+                .flatten()
+                .collect(),
+            }),
         }
     }
 }
@@ -247,13 +730,17 @@ impl LdapMsg {
             ctrl: Vec::new(),
         }
     }
-}
-
-impl TryFrom<StructureTag> for LdapMsg {
-    type Error = ();
 
+    /// Decode a message, separating errors that only affect this message from
+    /// those that mean the stream can't be trusted. Once the envelope,
+    /// messageID and protocolOp tag are known to be good, a failure in the
+    /// body of the op or its controls is returned as an `LdapMalformedMsg` so
+    /// the server can reply to that request and carry on.
+    ///
     /// https://tools.ietf.org/html/rfc4511#section-4.1.1
-    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+    pub fn try_from_recoverable(
+        value: StructureTag,
+    ) -> Result<Result<Self, LdapMalformedMsg>, LdapProtoError> {
         /*
          * LDAPMessage ::= SEQUENCE {
          *      messageID       MessageID,
@@ -286,10 +773,10 @@ impl TryFrom<StructureTag> for LdapMsg {
          *
          * maxInt INTEGER ::= 2147483647 -- (2^^31 - 1) --
          */
-        let mut seq = value
-            .match_id(Types::Sequence as u64)
-            .and_then(|t| t.expect_constructed())
-            .ok_or(())?;
+        let mut seq = decode_field(Some(value), "LDAPMessage", "", |t| {
+            t.match_id(Types::Sequence as u64)
+                .and_then(|t| t.expect_constructed())
+        })?;
 
         // seq is now a vec of the inner elements.
         let (msgid_tag, op_tag, ctrl_tag) = match seq.len() {
@@ -307,31 +794,105 @@ impl TryFrom<StructureTag> for LdapMsg {
                 let m = seq.pop();
                 (m, o, c)
             }
-            _ => return Err(()),
+            _ => {
+                return Err(match seq.get(3) {
+                    Some(t) => LdapProtoError::Unexpected {
+                        structure: "LDAPMessage",
+                        class: t.class,
+                        id: t.id,
+                    },
+                    None => LdapProtoError::Missing {
+                        structure: "LDAPMessage",
+                        field: "protocolOp",
+                    },
+                })
+            }
         };
 
         // The first item should be the messageId
-        let msgid = msgid_tag
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Integer as u64))
-            // Get the raw bytes
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            // Trunc to i32.
-            .map(|i| i as i32)
-            .ok_or(())?;
-
-        let op = op_tag.ok_or(())?;
-        let op = LdapOp::try_from(op)?;
-
-        let ctrl = ctrl_tag
+        let msgid = decode_field(msgid_tag, "LDAPMessage", "messageID", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Integer as u64))
+                // Get the raw bytes
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+                // Trunc to i32.
+                .map(|i| i as i32)
+        })?;
+
+        let op = op_tag.ok_or(LdapProtoError::Missing {
+            structure: "LDAPMessage",
+            field: "protocolOp",
+        })?;
+        let op_id = op.id;
+        let op = match LdapOp::try_from(op) {
+            Ok(op) => op,
+            // If we don't know what the op is, we can't know how the client
+            // expects us to respond.
+            Err(e @ LdapProtoError::UnknownOp { .. }) => return Err(e),
+            Err(err) => return Ok(Err(LdapMalformedMsg { msgid, op_id, err })),
+        };
+
+        let ctrl = match ctrl_tag
             .and_then(|t| t.match_class(TagClass::Context))
             .and_then(|t| t.match_id(0))
-            // So it's probably controls, decode them?
-            .map(|_t| Vec::new())
-            .unwrap_or_else(|| Vec::new());
+        {
+            // So it's probably controls, decode them.
+            Some(t) => {
+                let r: Result<Vec<_>, _> = decode_field(Some(t), "LDAPMessage", "controls", |t| {
+                    t.expect_constructed()
+                })
+                .and_then(|c| c.into_iter().map(LdapControl::try_from).collect());
+                match r {
+                    Ok(c) => c,
+                    Err(err) => return Ok(Err(LdapMalformedMsg { msgid, op_id, err })),
+                }
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Ok(LdapMsg { msgid, op, ctrl }))
+    }
+}
 
-        Ok(LdapMsg { msgid, op, ctrl })
+impl TryFrom<StructureTag> for LdapMsg {
+    type Error = LdapProtoError;
+
+    /// https://tools.ietf.org/html/rfc4511#section-4.1.1
+    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        LdapMsg::try_from_recoverable(value)?.map_err(|m| m.err)
+    }
+}
+
+impl LdapMalformedMsg {
+    /// The protocolError response to the malformed request, or None if the
+    /// request was one that has no response, such as an abandon.
+    pub fn gen_protocol_error(&self) -> Option<LdapMsg> {
+        let res = LdapResult {
+            code: LdapResultCode::ProtocolError,
+            matcheddn: "".to_string(),
+            message: self.err.to_string(),
+            referral: Vec::new(),
+        };
+        let op = match self.op_id {
+            0 => LdapOp::BindResponse(LdapBindResponse {
+                res,
+                saslcreds: None,
+            }),
+            3 => LdapOp::SearchResultDone(res),
+            6 => LdapOp::ModifyResponse(res),
+            8 => LdapOp::AddResponse(res),
+            10 => LdapOp::DelResponse(res),
+            12 => LdapOp::ModifyDNResponse(res),
+            14 => LdapOp::CompareResponse(res),
+            23 => LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res,
+                name: None,
+                value: None,
+            }),
+            _ => return None,
+        };
+        Some(LdapMsg::new(self.msgid, op))
     }
 }
 
@@ -346,13 +907,17 @@ impl From<LdapMsg> for StructureTag {
         })
         .chain(once_with(|| Some(op.into())))
         .chain(once_with(|| {
-            if ctrl.len() > 0 {
-                unimplemented!();
+            if !ctrl.is_empty() {
+                Some(Tag::Sequence(Sequence {
+                    id: 0,
+                    class: TagClass::Context,
+                    inner: ctrl.into_iter().map(|v| v.into()).collect(),
+                }))
             } else {
                 None
             }
         }))
-        .filter_map(|v| v)
+        .flatten()
         .collect();
         Tag::Sequence(Sequence {
             inner: seq,
@@ -362,58 +927,273 @@ impl From<LdapMsg> for StructureTag {
     }
 }
 
+impl TryFrom<StructureTag> for LdapControl {
+    type Error = LdapProtoError;
+
+    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        let mut inner = decode_field(Some(value), "Control", "", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?;
+
+        inner.reverse();
+
+        let oid = decode_field(inner.pop(), "Control", "controlType", decode_string)?;
+
+        // criticality is BOOLEAN DEFAULT FALSE, and the value is optional,
+        // so we have to check what we have been given.
+        let mut criticality = false;
+        let mut seen_criticality = false;
+        let mut value = None;
+        while let Some(t) = inner.pop() {
+            match (t.class, t.id) {
+                (TagClass::Universal, id)
+                    if id == Types::Boolean as u64 && value.is_none() && !seen_criticality =>
+                {
+                    seen_criticality = true;
+                    criticality = decode_field(Some(t), "Control", "criticality", |t| {
+                        t.expect_primitive().and_then(ber_bool_to_bool)
+                    })?;
+                }
+                (TagClass::Universal, id) if id == Types::OctetString as u64 && value.is_none() => {
+                    value = decode_field(Some(t), "Control", "controlValue", |t| {
+                        t.expect_primitive().map(Some)
+                    })?;
+                }
+                (class, id) => {
+                    return Err(LdapProtoError::Unexpected {
+                        structure: "Control",
+                        class,
+                        id,
+                    })
+                }
+            }
+        }
+
+        Ok(LdapControl {
+            oid,
+            criticality,
+            value,
+        })
+    }
+}
+
+impl From<LdapControl> for Tag {
+    fn from(value: LdapControl) -> Tag {
+        let LdapControl {
+            oid,
+            criticality,
+            value,
+        } = value;
+
+        let inner = once_with(|| {
+            Some(Tag::OctetString(OctetString {
+                inner: Vec::from(oid),
+                ..Default::default()
+            }))
+        })
+        .chain(once_with(|| {
+            // DEFAULT FALSE, so we only need to send this when it's true.
+            if criticality {
+                Some(Tag::Boolean(Boolean {
+                    inner: criticality,
+                    ..Default::default()
+                }))
+            } else {
+                None
+            }
+        }))
+        .chain(once_with(|| {
+            value.map(|v| {
+                Tag::OctetString(OctetString {
+                    inner: v,
+                    ..Default::default()
+                })
+            })
+        }))
+        .flatten()
+        .collect();
+
+        Tag::Sequence(Sequence {
+            inner,
+            ..Default::default()
+        })
+    }
+}
+
+impl LdapPagedResults {
+    pub const OID: &'static str = "1.2.840.113556.1.4.319";
+}
+
+impl TryFrom<&LdapControl> for LdapPagedResults {
+    type Error = LdapProtoError;
+
+    fn try_from(ctrl: &LdapControl) -> Result<Self, Self::Error> {
+        // The value is itself BER, so anything wrong with the control is
+        // reported against the octet string that holds it.
+        let value_err = |field| LdapProtoError::Invalid {
+            structure: "Control",
+            field,
+            class: TagClass::Universal,
+            id: Types::OctetString as u64,
+        };
+        if ctrl.oid != LdapPagedResults::OID {
+            return Err(value_err("controlType"));
+        }
+        let value = ctrl.value.as_ref().ok_or(LdapProtoError::Missing {
+            structure: "Control",
+            field: "controlValue",
+        })?;
+        let tag = match parse_tag(value) {
+            IResult::Done([], tag) => tag,
+            _ => return Err(value_err("controlValue")),
+        };
+
+        /*
+         * realSearchControlValue ::= SEQUENCE {
+         *         size            INTEGER (0..maxInt),
+         *                                 -- requested page size from client
+         *                                 -- result set size estimate from server
+         *         cookie          OCTET STRING
+         * }
+         */
+        let mut inner = decode_field(Some(tag), "realSearchControlValue", "", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?;
+        inner.reverse();
+
+        let size = decode_field(inner.pop(), "realSearchControlValue", "size", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Integer as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+        })?;
+        if size < 0 || size > i32::MAX as i64 {
+            return Err(LdapProtoError::OutOfRange {
+                structure: "realSearchControlValue",
+                field: "size",
+                value: size,
+            });
+        }
+
+        let cookie = decode_field(
+            inner.pop(),
+            "realSearchControlValue",
+            "cookie",
+            decode_octets,
+        )?;
+
+        Ok(LdapPagedResults {
+            size: size as i32,
+            cookie,
+        })
+    }
+}
+
+impl From<LdapPagedResults> for LdapControl {
+    fn from(value: LdapPagedResults) -> LdapControl {
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf);
+        LdapControl {
+            oid: LdapPagedResults::OID.to_string(),
+            criticality: false,
+            value: Some(buf.to_vec()),
+        }
+    }
+}
+
 impl TryFrom<StructureTag> for LdapOp {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
         let StructureTag { class, id, payload } = value;
         if class != TagClass::Application {
-            return Err(());
+            return Err(LdapProtoError::UnknownOp { class, id });
         }
         match (id, payload) {
             // https://tools.ietf.org/html/rfc4511#section-4.2
             // BindRequest
-            (0, PL::C(inner)) => LdapBindRequest::try_from(inner).map(|v| LdapOp::BindRequest(v)),
+            (0, PL::C(inner)) => LdapBindRequest::try_from(inner).map(LdapOp::BindRequest),
             // BindResponse
-            (1, PL::C(inner)) => LdapBindResponse::try_from(inner).map(|v| LdapOp::BindResponse(v)),
+            (1, PL::C(inner)) => LdapBindResponse::try_from(inner).map(LdapOp::BindResponse),
             // UnbindRequest
             (2, _) => Ok(LdapOp::UnbindRequest),
-            (3, PL::C(inner)) => {
-                LdapSearchRequest::try_from(inner).map(|v| LdapOp::SearchRequest(v))
-            }
+            (3, PL::C(inner)) => LdapSearchRequest::try_from(inner).map(LdapOp::SearchRequest),
             (4, PL::C(inner)) => {
-                LdapSearchResultEntry::try_from(inner).map(|v| LdapOp::SearchResultEntry(v))
+                LdapSearchResultEntry::try_from(inner).map(LdapOp::SearchResultEntry)
             }
             (5, PL::C(inner)) => {
                 LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::SearchResultDone(lr))
             }
+            (6, PL::C(inner)) => LdapModifyRequest::try_from(inner).map(LdapOp::ModifyRequest),
             (7, PL::C(inner)) => {
                 LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::ModifyResponse(lr))
             }
-            (8, PL::C(inner)) => LdapAddRequest::try_from(inner).map(|v| LdapOp::AddRequest(v)),
+            (8, PL::C(inner)) => LdapAddRequest::try_from(inner).map(LdapOp::AddRequest),
             (9, PL::C(inner)) => {
                 LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::AddResponse(lr))
             }
             (10, PL::P(inner)) => String::from_utf8(inner)
-                .ok()
-                .ok_or(())
-                .map(|s| LdapOp::DelRequest(s)),
+                .map(LdapOp::DelRequest)
+                .map_err(|_| LdapProtoError::Invalid {
+                    structure: "DelRequest",
+                    field: "",
+                    class,
+                    id,
+                }),
             (11, PL::C(inner)) => {
                 LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::DelResponse(lr))
             }
+            (12, PL::C(inner)) => LdapModifyDNRequest::try_from(inner).map(LdapOp::ModifyDNRequest),
+            (13, PL::C(inner)) => {
+                LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::ModifyDNResponse(lr))
+            }
+            (14, PL::C(inner)) => LdapCompareRequest::try_from(inner).map(LdapOp::CompareRequest),
+            (15, PL::C(inner)) => {
+                LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::CompareResponse(lr))
+            }
             (16, PL::P(inner)) => ber_integer_to_i64(inner)
-                .ok_or(())
-                .map(|s| LdapOp::AbandonRequest(s as i32)),
-            (23, PL::C(inner)) => {
-                LdapExtendedRequest::try_from(inner).map(|v| LdapOp::ExtendedRequest(v))
+                .map(|s| LdapOp::AbandonRequest(s as i32))
+                .ok_or(LdapProtoError::Invalid {
+                    structure: "AbandonRequest",
+                    field: "",
+                    class,
+                    id,
+                }),
+            (19, PL::C(inner)) => {
+                let uris = inner
+                    .into_iter()
+                    .map(|t| decode_field(Some(t), "SearchResultReference", "uri", decode_string))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // SIZE (1..MAX), so at least one uri is required.
+                if uris.is_empty() {
+                    Err(LdapProtoError::Missing {
+                        structure: "SearchResultReference",
+                        field: "uri",
+                    })
+                } else {
+                    Ok(LdapOp::SearchResultReference(uris))
+                }
             }
+            (23, PL::C(inner)) => LdapExtendedRequest::try_from(inner).map(LdapOp::ExtendedRequest),
             (24, PL::C(inner)) => {
-                LdapExtendedResponse::try_from(inner).map(|v| LdapOp::ExtendedResponse(v))
+                LdapExtendedResponse::try_from(inner).map(LdapOp::ExtendedResponse)
             }
-            (id, _) => {
-                println!("unknown op -> {:?}", id);
-                Err(())
+            (25, PL::C(inner)) => {
+                LdapIntermediateResponse::try_from(inner).map(LdapOp::IntermediateResponse)
             }
+            // A known op, but constructed where it should be primitive or
+            // the other way around.
+            (id @ (0..=16 | 19 | 23..=25), _) => Err(LdapProtoError::Invalid {
+                structure: "LDAPMessage",
+                field: "protocolOp",
+                class,
+                id,
+            }),
+            (id, _) => Err(LdapProtoError::UnknownOp { class, id }),
         }
     }
 }
@@ -451,6 +1231,19 @@ impl From<LdapOp> for Tag {
                 id: 5,
                 inner: lr.into(),
             }),
+            LdapOp::SearchResultReference(uris) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 19,
+                inner: uris
+                    .into_iter()
+                    .map(|v| {
+                        Tag::OctetString(OctetString {
+                            inner: Vec::from(v),
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+            }),
             LdapOp::ModifyRequest(mr) => Tag::Sequence(Sequence {
                 class: TagClass::Application,
                 id: 6,
@@ -481,6 +1274,26 @@ impl From<LdapOp> for Tag {
                 id: 11,
                 inner: lr.into(),
             }),
+            LdapOp::ModifyDNRequest(mdr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 12,
+                inner: mdr.into(),
+            }),
+            LdapOp::ModifyDNResponse(lr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 13,
+                inner: lr.into(),
+            }),
+            LdapOp::CompareRequest(cr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 14,
+                inner: cr.into(),
+            }),
+            LdapOp::CompareResponse(lr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 15,
+                inner: lr.into(),
+            }),
             LdapOp::AbandonRequest(id) => Tag::Integer(Integer {
                 class: TagClass::Application,
                 id: 16,
@@ -496,65 +1309,99 @@ impl From<LdapOp> for Tag {
                 id: 24,
                 inner: ler.into(),
             }),
+            LdapOp::IntermediateResponse(lir) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 25,
+                inner: lir.into(),
+            }),
         }
     }
 }
 
 impl TryFrom<StructureTag> for LdapBindCred {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
-        if value.class != TagClass::Context {
-            return Err(());
-        }
+        match (value.class, value.id) {
+            (TagClass::Context, 0) => {
+                decode_field(Some(value), "AuthenticationChoice", "simple", |t| {
+                    t.expect_primitive()
+                        .and_then(|bv| String::from_utf8(bv).ok())
+                })
+                .map(LdapBindCred::Simple)
+            }
+            (TagClass::Context, 3) => {
+                let mut inner = decode_field(Some(value), "AuthenticationChoice", "sasl", |t| {
+                    t.expect_constructed()
+                })?;
+                inner.reverse();
 
-        match value.id {
-            0 => value
-                .expect_primitive()
-                .and_then(|bv| String::from_utf8(bv).ok())
-                .map(|pw| LdapBindCred::Simple(pw))
-                .ok_or(()),
-            _ => Err(()),
+                let mechanism =
+                    decode_field(inner.pop(), "SaslCredentials", "mechanism", decode_string)?;
+
+                let credentials = match inner.pop() {
+                    Some(t) => Some(decode_field(
+                        Some(t),
+                        "SaslCredentials",
+                        "credentials",
+                        decode_octets,
+                    )?),
+                    None => None,
+                };
+
+                Ok(LdapBindCred::Sasl {
+                    mechanism,
+                    credentials,
+                })
+            }
+            (class, id) => Err(LdapProtoError::Invalid {
+                structure: "BindRequest",
+                field: "authentication",
+                class,
+                id,
+            }),
         }
     }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapBindRequest {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         // https://tools.ietf.org/html/rfc4511#section-4.2
         // BindRequest
         value.reverse();
 
-        // Check the version is 3
-        let v = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Integer as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .ok_or(())?;
-        if v != 3 {
-            return Err(());
+        // version INTEGER (1 .. 127). We keep whatever was sent so that the
+        // server can refuse anything other than 3 with a bind response.
+        let v = decode_field(value.pop(), "BindRequest", "version", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Integer as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+        })?;
+        if !(1..=127).contains(&v) {
+            return Err(LdapProtoError::OutOfRange {
+                structure: "BindRequest",
+                field: "version",
+                value: v,
+            });
         };
+        let version = v as i32;
 
         // Get the DN
-        let dn = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let dn = decode_field(value.pop(), "BindRequest", "name", decode_string)?;
 
         // Andddd get the credential
         let cred = value
             .pop()
-            .and_then(|v| LdapBindCred::try_from(v).ok())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::Missing {
+                structure: "BindRequest",
+                field: "authentication",
+            })
+            .and_then(LdapBindCred::try_from)?;
 
-        Ok(LdapBindRequest { dn, cred })
+        Ok(LdapBindRequest { version, dn, cred })
     }
 }
 
@@ -562,7 +1409,7 @@ impl From<LdapBindRequest> for Vec<Tag> {
     fn from(value: LdapBindRequest) -> Vec<Tag> {
         vec![
             Tag::Integer(Integer {
-                inner: 3,
+                inner: value.version as i64,
                 ..Default::default()
             }),
             Tag::OctetString(OctetString {
@@ -582,10 +1429,11 @@ impl LdapResult {
             message,
             referral,
         } = self;
+        let code = i64::from(&code);
 
-        once_with(|| {
+        once_with(move || {
             Some(Tag::Enumerated(Enumerated {
-                inner: code as i64,
+                inner: code,
                 ..Default::default()
             }))
         })
@@ -602,9 +1450,20 @@ impl LdapResult {
             }))
         }))
         .chain(once_with(move || {
-            if referral.len() > 0 {
-                // Remember to mark this as id 3, class::Context  (I think)
-                unimplemented!();
+            if !referral.is_empty() {
+                Some(Tag::Sequence(Sequence {
+                    id: 3,
+                    class: TagClass::Context,
+                    inner: referral
+                        .into_iter()
+                        .map(|v| {
+                            Tag::OctetString(OctetString {
+                                inner: Vec::from(v),
+                                ..Default::default()
+                            })
+                        })
+                        .collect(),
+                }))
             } else {
                 None
             }
@@ -615,44 +1474,65 @@ impl LdapResult {
 impl From<LdapResult> for Vec<Tag> {
     fn from(value: LdapResult) -> Vec<Tag> {
         // get all the values from the LdapResult
-        value.into_tag_iter().filter_map(|s| s).collect()
+        value.into_tag_iter().flatten().collect()
     }
 }
 
 impl LdapResult {
-    fn try_from_tag(mut value: Vec<StructureTag>) -> Result<(Self, Vec<StructureTag>), ()> {
+    fn try_from_tag(
+        mut value: Vec<StructureTag>,
+    ) -> Result<(Self, Vec<StructureTag>), LdapProtoError> {
         // First, reverse all the elements so we are in the correct order.
         value.reverse();
 
-        let code = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Enumerated as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .ok_or(())
-            .and_then(|i| LdapResultCode::try_from(i))?;
-
-        let matcheddn = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let code = decode_field(value.pop(), "LDAPResult", "resultCode", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Enumerated as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+        })?;
+        let code = LdapResultCode::from(code);
 
-        let message = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let matcheddn = decode_field(value.pop(), "LDAPResult", "matchedDN", decode_string)?;
+
+        let message = decode_field(
+            value.pop(),
+            "LDAPResult",
+            "diagnosticMessage",
+            decode_string,
+        )?;
 
-        let (_referrals, other): (Vec<_>, Vec<_>) = value.into_iter().partition(|v| v.id == 3);
+        let (mut referrals, other): (Vec<_>, Vec<_>) = value
+            .into_iter()
+            .partition(|v| v.id == 3 && v.class == TagClass::Context);
 
         // assert referrals only is one
-        let referral = Vec::new();
+        let referral = match referrals.pop() {
+            Some(t) if referrals.is_empty() => {
+                let r = decode_field(Some(t), "LDAPResult", "referral", |t| {
+                    t.expect_constructed()
+                })?
+                .into_iter()
+                .map(|t| decode_field(Some(t), "LDAPResult", "referral", decode_string))
+                .collect::<Result<Vec<_>, _>>()?;
+                // SIZE (1..MAX)
+                if r.is_empty() {
+                    return Err(LdapProtoError::Missing {
+                        structure: "LDAPResult",
+                        field: "referral",
+                    });
+                }
+                r
+            }
+            Some(t) => {
+                return Err(LdapProtoError::Unexpected {
+                    structure: "LDAPResult",
+                    class: t.class,
+                    id: t.id,
+                })
+            }
+            None => Vec::new(),
+        };
 
         Ok((
             LdapResult {
@@ -693,17 +1573,23 @@ impl LdapBindResponse {
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapBindResponse {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         // This MUST be the first thing we do!
-        let (res, _remtag) = LdapResult::try_from_tag(value)?;
+        let (res, remtag) = LdapResult::try_from_tag(value)?;
 
         // Now with the remaining tags, populate anything else we need
-        Ok(LdapBindResponse {
-            res,
-            saslcreds: None,
-        })
+        let mut saslcreds = None;
+        for v in remtag.into_iter() {
+            if let (7, TagClass::Context) = (v.id, v.class) {
+                saslcreds = decode_field(Some(v), "BindResponse", "serverSaslCreds", |t| {
+                    t.expect_primitive().map(Some)
+                })?;
+            }
+        }
+
+        Ok(LdapBindResponse { res, saslcreds })
     }
 }
 
@@ -715,134 +1601,220 @@ impl From<LdapBindResponse> for Vec<Tag> {
             .chain(once_with(|| {
                 saslcreds.map(|sc| {
                     Tag::OctetString(OctetString {
-                        inner: Vec::from(sc),
-                        ..Default::default()
+                        id: 7,
+                        class: TagClass::Context,
+                        inner: sc,
                     })
                 })
             }))
-            .filter_map(|s| s)
+            .flatten()
             .collect()
     }
 }
 
+impl LdapFilter {
+    /// The assertion value as utf8 text, for filters that carry one.
+    pub fn assertion_str(&self) -> Option<&str> {
+        match self {
+            LdapFilter::Equality(_, v)
+            | LdapFilter::GreaterOrEqual(_, v)
+            | LdapFilter::LessOrEqual(_, v)
+            | LdapFilter::Approx(_, v)
+            | LdapFilter::Extensible { value: v, .. } => std::str::from_utf8(v).ok(),
+            _ => None,
+        }
+    }
+}
+
 impl TryFrom<StructureTag> for LdapFilter {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
         if value.class != TagClass::Context {
-            return Err(());
+            return Err(LdapProtoError::Invalid {
+                structure: "Filter",
+                field: "",
+                class: value.class,
+                id: value.id,
+            });
         };
 
         match value.id {
             0 => {
-                let inner = value.expect_constructed().ok_or(())?;
-                let vf: Result<Vec<_>, _> =
-                    inner.into_iter().map(|v| LdapFilter::try_from(v)).collect();
+                let inner = decode_field(Some(value), "Filter", "and", |t| t.expect_constructed())?;
+                let vf: Result<Vec<_>, _> = inner.into_iter().map(LdapFilter::try_from).collect();
                 Ok(LdapFilter::And(vf?))
             }
             1 => {
-                let inner = value.expect_constructed().ok_or(())?;
-                let vf: Result<Vec<_>, _> =
-                    inner.into_iter().map(|v| LdapFilter::try_from(v)).collect();
+                let inner = decode_field(Some(value), "Filter", "or", |t| t.expect_constructed())?;
+                let vf: Result<Vec<_>, _> = inner.into_iter().map(LdapFilter::try_from).collect();
                 Ok(LdapFilter::Or(vf?))
             }
             2 => {
-                let inner = value
-                    .expect_constructed()
-                    .and_then(|mut i| i.pop())
-                    .ok_or(())?;
+                let inner = decode_field(Some(value), "Filter", "not", |t| {
+                    t.expect_constructed().and_then(|mut i| i.pop())
+                })?;
                 let inner_filt = LdapFilter::try_from(inner)?;
                 Ok(LdapFilter::Not(Box::new(inner_filt)))
             }
-            3 => {
-                let mut inner = value.expect_constructed().ok_or(())?;
+            // These are all AttributeValueAssertions
+            id @ (3 | 5 | 6 | 8) => {
+                let mut inner = decode_field(Some(value), "AttributeValueAssertion", "", |t| {
+                    t.expect_constructed()
+                })?;
                 inner.reverse();
 
-                let a = inner
-                    .pop()
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .and_then(|bv| String::from_utf8(bv).ok())
-                    .ok_or(())?;
-
-                let v = inner
-                    .pop()
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .and_then(|bv| String::from_utf8(bv).ok())
-                    .ok_or(())?;
-
-                Ok(LdapFilter::Equality(a, v))
+                let a = decode_field(
+                    inner.pop(),
+                    "AttributeValueAssertion",
+                    "attributeDesc",
+                    decode_string,
+                )?;
+
+                let v = decode_field(
+                    inner.pop(),
+                    "AttributeValueAssertion",
+                    "assertionValue",
+                    decode_octets,
+                )?;
+
+                match id {
+                    3 => Ok(LdapFilter::Equality(a, v)),
+                    5 => Ok(LdapFilter::GreaterOrEqual(a, v)),
+                    6 => Ok(LdapFilter::LessOrEqual(a, v)),
+                    _ => Ok(LdapFilter::Approx(a, v)),
+                }
             }
             4 => {
-                let mut inner = value.expect_constructed().ok_or(())?;
+                let mut inner = decode_field(Some(value), "SubstringFilter", "", |t| {
+                    t.expect_constructed()
+                })?;
                 inner.reverse();
 
-                let ty = inner
-                    .pop()
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .and_then(|bv| String::from_utf8(bv).ok())
-                    .ok_or(())?;
-
-                let f = inner
-                    .pop()
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::Sequence as u64))
-                    .and_then(|t| t.expect_constructed())
-                    .and_then(|bv| {
-                        let mut filter = LdapSubstringFilter::default();
-                        for (
-                            i,
-                            StructureTag {
-                                class: _,
-                                id,
-                                payload,
-                            },
-                        ) in bv.iter().enumerate()
-                        {
-                            match (id, payload) {
-                                (0, PL::P(s)) => {
-                                    if i == 0 {
-                                        // If 'initial' is present, it SHALL
-                                        // be the first element of 'substrings'.
-                                        filter.initial = Some(String::from_utf8(s.clone()).ok()?);
-                                    } else {
-                                        return None;
-                                    }
-                                }
-                                (1, PL::P(s)) => {
-                                    filter.any.push(String::from_utf8(s.clone()).ok()?);
-                                }
-                                (2, PL::P(s)) => {
-                                    if i == bv.len() - 1 {
-                                        // If 'final' is present, it
-                                        // SHALL be the last element of 'substrings'.
-                                        filter.final_ = Some(String::from_utf8(s.clone()).ok()?);
-                                    } else {
-                                        return None;
-                                    }
-                                }
-                                _ => return None,
+                let ty = decode_field(inner.pop(), "SubstringFilter", "type", decode_string)?;
+
+                let f = decode_field(inner.pop(), "SubstringFilter", "substrings", |t| {
+                    t.match_class(TagClass::Universal)
+                        .and_then(|t| t.match_id(Types::Sequence as u64))
+                        .and_then(|t| t.expect_constructed())
+                })
+                .and_then(|bv| {
+                    // Only the id of each choice is checked, so that the
+                    // universal tags that older versions of this crate sent
+                    // are still understood. A strict codec rejects them.
+                    let mut filter = LdapSubstringFilter::default();
+                    for (i, StructureTag { class, id, payload }) in bv.iter().enumerate() {
+                        match (id, payload) {
+                            (0, PL::P(s)) if i == 0 => {
+                                // If 'initial' is present, it SHALL
+                                // be the first element of 'substrings'.
+                                filter.initial = Some(s.clone());
+                            }
+                            (1, PL::P(s)) => {
+                                filter.any.push(s.clone());
+                            }
+                            (2, PL::P(s)) if i == bv.len() - 1 => {
+                                // If 'final' is present, it
+                                // SHALL be the last element of 'substrings'.
+                                filter.final_ = Some(s.clone());
+                            }
+                            _ => {
+                                return Err(LdapProtoError::Unexpected {
+                                    structure: "SubstringFilter",
+                                    class: *class,
+                                    id: *id,
+                                })
                             }
                         }
-                        Some(filter)
-                    })
-                    .ok_or(())?;
+                    }
+                    Ok(filter)
+                })?;
 
                 Ok(LdapFilter::Substring(ty, f))
             }
             7 => {
-                let a = value
-                    .expect_primitive()
-                    .and_then(|bv| String::from_utf8(bv).ok())
-                    .ok_or(())?;
+                let a = decode_field(Some(value), "Filter", "present", |t| {
+                    t.expect_primitive()
+                        .and_then(|bv| String::from_utf8(bv).ok())
+                })?;
                 Ok(LdapFilter::Present(a))
             }
-            _ => Err(()),
+            9 => {
+                let inner = decode_field(Some(value), "MatchingRuleAssertion", "", |t| {
+                    t.expect_constructed()
+                })?;
+
+                let mut rule = None;
+                let mut atype = None;
+                let mut value = None;
+                let mut dn_attributes = false;
+                // Each element must be in order, and may only appear once.
+                let mut last_id = 0;
+                for t in inner.into_iter() {
+                    if t.class != TagClass::Context || t.id <= last_id || t.id > 4 {
+                        return Err(LdapProtoError::Unexpected {
+                            structure: "MatchingRuleAssertion",
+                            class: t.class,
+                            id: t.id,
+                        });
+                    }
+                    last_id = t.id;
+                    let utf8 = |t: StructureTag| {
+                        t.expect_primitive()
+                            .and_then(|bv| String::from_utf8(bv).ok())
+                    };
+                    let t = Some(t);
+                    match last_id {
+                        1 => {
+                            rule = Some(decode_field(
+                                t,
+                                "MatchingRuleAssertion",
+                                "matchingRule",
+                                utf8,
+                            )?)
+                        }
+                        2 => atype = Some(decode_field(t, "MatchingRuleAssertion", "type", utf8)?),
+                        3 => {
+                            value = Some(decode_field(
+                                t,
+                                "MatchingRuleAssertion",
+                                "matchValue",
+                                |t| t.expect_primitive(),
+                            )?)
+                        }
+                        _ => {
+                            dn_attributes =
+                                decode_field(t, "MatchingRuleAssertion", "dnAttributes", |t| {
+                                    t.expect_primitive().and_then(ber_bool_to_bool)
+                                })?
+                        }
+                    }
+                }
+
+                // If the matchingRule is absent, the type MUST be present.
+                if rule.is_none() && atype.is_none() {
+                    return Err(LdapProtoError::Missing {
+                        structure: "MatchingRuleAssertion",
+                        field: "type",
+                    });
+                }
+
+                Ok(LdapFilter::Extensible {
+                    rule,
+                    atype,
+                    value: value.ok_or(LdapProtoError::Missing {
+                        structure: "MatchingRuleAssertion",
+                        field: "matchValue",
+                    })?,
+                    dn_attributes,
+                })
+            }
+            id => Err(LdapProtoError::Invalid {
+                structure: "Filter",
+                field: "",
+                class: TagClass::Context,
+                id,
+            }),
         }
     }
 }
@@ -874,7 +1846,7 @@ impl From<LdapFilter> for Tag {
                         ..Default::default()
                     }),
                     Tag::OctetString(OctetString {
-                        inner: Vec::from(v),
+                        inner: v,
                         ..Default::default()
                     }),
                 ],
@@ -893,23 +1865,23 @@ impl From<LdapFilter> for Tag {
                             .into_iter()
                             .map(|s| {
                                 Tag::OctetString(OctetString {
-                                    inner: Vec::from(s),
+                                    inner: s,
                                     id: 0,
-                                    ..Default::default()
+                                    class: TagClass::Context,
                                 })
                             })
                             .chain(f.any.into_iter().map(|s| {
                                 Tag::OctetString(OctetString {
-                                    inner: Vec::from(s),
+                                    inner: s,
                                     id: 1,
-                                    ..Default::default()
+                                    class: TagClass::Context,
                                 })
                             }))
                             .chain(f.final_.into_iter().map(|s| {
                                 Tag::OctetString(OctetString {
-                                    inner: Vec::from(s),
+                                    inner: s,
                                     id: 2,
-                                    ..Default::default()
+                                    class: TagClass::Context,
                                 })
                             }))
                             .collect(),
@@ -917,89 +1889,162 @@ impl From<LdapFilter> for Tag {
                     }),
                 ],
             }),
+            LdapFilter::GreaterOrEqual(a, v) => Tag::Sequence(Sequence {
+                id: 5,
+                class: TagClass::Context,
+                inner: vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(a),
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: v,
+                        ..Default::default()
+                    }),
+                ],
+            }),
+            LdapFilter::LessOrEqual(a, v) => Tag::Sequence(Sequence {
+                id: 6,
+                class: TagClass::Context,
+                inner: vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(a),
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: v,
+                        ..Default::default()
+                    }),
+                ],
+            }),
             LdapFilter::Present(a) => Tag::OctetString(OctetString {
                 id: 7,
                 class: TagClass::Context,
                 inner: Vec::from(a),
             }),
+            LdapFilter::Approx(a, v) => Tag::Sequence(Sequence {
+                id: 8,
+                class: TagClass::Context,
+                inner: vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(a),
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: v,
+                        ..Default::default()
+                    }),
+                ],
+            }),
+            LdapFilter::Extensible {
+                rule,
+                atype,
+                value,
+                dn_attributes,
+            } => Tag::Sequence(Sequence {
+                id: 9,
+                class: TagClass::Context,
+                inner: once_with(|| {
+                    rule.map(|r| {
+                        Tag::OctetString(OctetString {
+                            id: 1,
+                            class: TagClass::Context,
+                            inner: Vec::from(r),
+                        })
+                    })
+                })
+                .chain(once_with(|| {
+                    atype.map(|a| {
+                        Tag::OctetString(OctetString {
+                            id: 2,
+                            class: TagClass::Context,
+                            inner: Vec::from(a),
+                        })
+                    })
+                }))
+                .chain(once_with(|| {
+                    Some(Tag::OctetString(OctetString {
+                        id: 3,
+                        class: TagClass::Context,
+                        inner: value,
+                    }))
+                }))
+                .chain(once_with(|| {
+                    // DEFAULT FALSE, so we only need to send this when it's true.
+                    if dn_attributes {
+                        Some(Tag::Boolean(Boolean {
+                            id: 4,
+                            class: TagClass::Context,
+                            inner: dn_attributes,
+                        }))
+                    } else {
+                        None
+                    }
+                }))
+                .flatten()
+                .collect(),
+            }),
         }
     }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapSearchRequest {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         value.reverse();
 
-        let base = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
-        let scope = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Enumerated as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .ok_or(())
-            .and_then(|i| LdapSearchScope::try_from(i))?;
-        let aliases = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Enumerated as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .ok_or(())
-            .and_then(|i| LdapDerefAliases::try_from(i))?;
-        let sizelimit = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Integer as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .map(|v| v as i32)
-            .ok_or(())?;
-        let timelimit = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Integer as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_integer_to_i64)
-            .map(|v| v as i32)
-            .ok_or(())?;
-        let typesonly = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Boolean as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(ber_bool_to_bool)
-            .ok_or(())?;
+        let enumerated = |t: StructureTag| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Enumerated as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+        };
+        let integer = |t: StructureTag| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Integer as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+                .map(|v| v as i32)
+        };
+
+        let base = decode_field(value.pop(), "SearchRequest", "baseObject", decode_string)?;
+        let scope = decode_field(value.pop(), "SearchRequest", "scope", enumerated)?;
+        let scope = LdapSearchScope::try_from(scope).map_err(|_| LdapProtoError::OutOfRange {
+            structure: "SearchRequest",
+            field: "scope",
+            value: scope,
+        })?;
+        let aliases = decode_field(value.pop(), "SearchRequest", "derefAliases", enumerated)?;
+        let aliases =
+            LdapDerefAliases::try_from(aliases).map_err(|_| LdapProtoError::OutOfRange {
+                structure: "SearchRequest",
+                field: "derefAliases",
+                value: aliases,
+            })?;
+        let sizelimit = decode_field(value.pop(), "SearchRequest", "sizeLimit", integer)?;
+        let timelimit = decode_field(value.pop(), "SearchRequest", "timeLimit", integer)?;
+        let typesonly = decode_field(value.pop(), "SearchRequest", "typesOnly", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Boolean as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_bool_to_bool)
+        })?;
         let filter = value
             .pop()
-            .and_then(|t| LdapFilter::try_from(t).ok())
-            .ok_or(())?;
-        let attrs = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Sequence as u64))
-            .and_then(|t| t.expect_constructed())
-            .and_then(|vs| {
-                let r: Option<Vec<_>> = vs
-                    .into_iter()
-                    .map(|bv| {
-                        bv.match_class(TagClass::Universal)
-                            .and_then(|t| t.match_id(Types::OctetString as u64))
-                            .and_then(|t| t.expect_primitive())
-                            .and_then(|bv| String::from_utf8(bv).ok())
-                    })
-                    .collect();
-                r
+            .ok_or(LdapProtoError::Missing {
+                structure: "SearchRequest",
+                field: "filter",
             })
-            .ok_or(())?;
+            .and_then(LdapFilter::try_from)?;
+        let attrs = decode_field(value.pop(), "SearchRequest", "attributes", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?
+        .into_iter()
+        .map(|t| decode_field(Some(t), "SearchRequest", "attributes", decode_string))
+        .collect::<Result<Vec<_>, _>>()?;
 
         Ok(LdapSearchRequest {
             base,
@@ -1069,77 +2114,65 @@ impl From<LdapSearchRequest> for Vec<Tag> {
     }
 }
 
+impl LdapPartialAttribute {
+    /// The values as utf8 text, or None if any value is not valid utf8.
+    pub fn str_vals(&self) -> Option<Vec<&str>> {
+        self.vals
+            .iter()
+            .map(|v| std::str::from_utf8(v).ok())
+            .collect()
+    }
+}
+
 impl TryFrom<StructureTag> for LdapPartialAttribute {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
         // get the inner from the sequence
-        let mut inner = value
-            .match_class(TagClass::Universal)
-            .and_then(|t| t.match_id(Types::Sequence as u64))
-            .and_then(|t| t.expect_constructed())
-            .ok_or(())?;
+        let mut inner = decode_field(Some(value), "PartialAttribute", "", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?;
 
         inner.reverse();
 
-        let atype = inner
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let atype = decode_field(inner.pop(), "PartialAttribute", "type", decode_string)?;
 
-        let vals = inner
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Set as u64))
-            .and_then(|t| t.expect_constructed())
-            .and_then(|bset| {
-                let r: Option<Vec<_>> = bset
-                    .into_iter()
-                    .map(|bv| {
-                        bv.match_class(TagClass::Universal)
-                            .and_then(|t| t.match_id(Types::OctetString as u64))
-                            .and_then(|t| t.expect_primitive())
-                            .and_then(|bv| String::from_utf8(bv).ok())
-                    })
-                    .collect();
-                r
-            })
-            .ok_or(())?;
+        let vals = decode_field(inner.pop(), "PartialAttribute", "vals", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Set as u64))
+                .and_then(|t| t.expect_constructed())
+        })?
+        .into_iter()
+        .map(|t| decode_field(Some(t), "PartialAttribute", "vals", decode_octets))
+        .collect::<Result<Vec<_>, _>>()?;
 
         Ok(LdapPartialAttribute { atype, vals })
     }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapSearchResultEntry {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         value.reverse();
 
-        let dn = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
-
-        let attributes = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Sequence as u64))
-            .and_then(|t| t.expect_constructed())
-            .and_then(|bset| {
-                let r: Result<Vec<_>, _> = bset
-                    .into_iter()
-                    .map(|bv| LdapPartialAttribute::try_from(bv))
-                    .collect();
-                r.ok()
-            })
-            .ok_or(())?;
+        let dn = decode_field(
+            value.pop(),
+            "SearchResultEntry",
+            "objectName",
+            decode_string,
+        )?;
+
+        let attributes = decode_field(value.pop(), "SearchResultEntry", "attributes", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?
+        .into_iter()
+        .map(LdapPartialAttribute::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
 
         Ok(LdapSearchResultEntry { dn, attributes })
     }
@@ -1159,7 +2192,7 @@ impl From<LdapPartialAttribute> for Tag {
                         .into_iter()
                         .map(|v| {
                             Tag::OctetString(OctetString {
-                                inner: Vec::from(v),
+                                inner: v,
                                 ..Default::default()
                             })
                         })
@@ -1189,19 +2222,18 @@ impl From<LdapSearchResultEntry> for Vec<Tag> {
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapExtendedRequest {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         // Put the values in order.
         value.reverse();
         // Read the values in
-        let name = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Context))
-            .and_then(|t| t.match_id(0))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let name = decode_field(value.pop(), "ExtendedRequest", "requestName", |t| {
+            t.match_class(TagClass::Context)
+                .and_then(|t| t.match_id(0))
+                .and_then(|t| t.expect_primitive())
+                .and_then(|bv| String::from_utf8(bv).ok())
+        })?;
 
         let value = value
             .pop()
@@ -1234,14 +2266,14 @@ impl From<LdapExtendedRequest> for Vec<Tag> {
                     })
                 })
             })
-            .filter_map(|s| s),
+            .flatten(),
         )
         .collect()
     }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapExtendedResponse {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         // This MUST be the first thing we do!
@@ -1289,7 +2321,7 @@ impl From<LdapExtendedResponse> for Vec<Tag> {
                     })
                 })
             }))
-            .filter_map(|s| s)
+            .flatten()
             .collect()
     }
 }
@@ -1304,7 +2336,7 @@ impl LdapExtendedResponse {
                 referral: Vec::new(),
             },
             name: name.map(|v| v.to_string()),
-            value: value.map(|v| Vec::from(v)),
+            value: value.map(Vec::from),
         }
     }
 
@@ -1322,6 +2354,71 @@ impl LdapExtendedResponse {
     }
 }
 
+impl TryFrom<Vec<StructureTag>> for LdapIntermediateResponse {
+    type Error = LdapProtoError;
+
+    fn try_from(value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        // Both fields are optional, so we have to check the tags.
+        let mut name = None;
+        let mut value_ = None;
+        for v in value.into_iter() {
+            match (v.id, v.class) {
+                (0, TagClass::Context) if name.is_none() && value_.is_none() => {
+                    name = decode_field(Some(v), "IntermediateResponse", "responseName", |t| {
+                        t.expect_primitive()
+                            .and_then(|bv| String::from_utf8(bv).ok())
+                            .map(Some)
+                    })?;
+                }
+                (1, TagClass::Context) if value_.is_none() => {
+                    value_ = decode_field(Some(v), "IntermediateResponse", "responseValue", |t| {
+                        t.expect_primitive().map(Some)
+                    })?;
+                }
+                (id, class) => {
+                    return Err(LdapProtoError::Unexpected {
+                        structure: "IntermediateResponse",
+                        class,
+                        id,
+                    })
+                }
+            }
+        }
+
+        Ok(LdapIntermediateResponse {
+            name,
+            value: value_,
+        })
+    }
+}
+
+impl From<LdapIntermediateResponse> for Vec<Tag> {
+    fn from(value: LdapIntermediateResponse) -> Vec<Tag> {
+        let LdapIntermediateResponse { name, value } = value;
+
+        once_with(|| {
+            name.map(|v| {
+                Tag::OctetString(OctetString {
+                    id: 0,
+                    class: TagClass::Context,
+                    inner: Vec::from(v),
+                })
+            })
+        })
+        .chain(once_with(|| {
+            value.map(|v| {
+                Tag::OctetString(OctetString {
+                    id: 1,
+                    class: TagClass::Context,
+                    inner: v,
+                })
+            })
+        }))
+        .flatten()
+        .collect()
+    }
+}
+
 impl TryFrom<i64> for LdapSearchScope {
     type Error = ();
 
@@ -1350,34 +2447,86 @@ impl TryFrom<i64> for LdapDerefAliases {
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapAddRequest {
-    type Error = ();
+    type Error = LdapProtoError;
 
     fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         value.reverse();
 
-        let dn = value
-            .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::OctetString as u64))
-            .and_then(|t| t.expect_primitive())
-            .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+        let dn = decode_field(value.pop(), "AddRequest", "entry", decode_string)?;
+
+        let attributes = decode_field(value.pop(), "AddRequest", "attributes", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?
+        .into_iter()
+        .map(LdapAttribute::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(LdapAddRequest { dn, attributes })
+    }
+}
+
+impl TryFrom<StructureTag> for LdapModify {
+    type Error = LdapProtoError;
+
+    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        // get the inner from the sequence
+        let mut inner = decode_field(Some(value), "ModifyRequest", "changes", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?;
+
+        inner.reverse();
 
-        let attributes = value
+        let operation = decode_field(inner.pop(), "ModifyRequest", "operation", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Enumerated as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_integer_to_i64)
+        })?;
+        let operation =
+            LdapModifyType::try_from(operation).map_err(|_| LdapProtoError::OutOfRange {
+                structure: "ModifyRequest",
+                field: "operation",
+                value: operation,
+            })?;
+
+        let modification = inner
             .pop()
-            .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Sequence as u64))
-            .and_then(|t| t.expect_constructed())
-            .and_then(|bset| {
-                let r: Result<Vec<_>, _> = bset
-                    .into_iter()
-                    .map(|bv| LdapAttribute::try_from(bv))
-                    .collect();
-                r.ok()
+            .ok_or(LdapProtoError::Missing {
+                structure: "ModifyRequest",
+                field: "modification",
             })
-            .ok_or(())?;
+            .and_then(LdapPartialAttribute::try_from)?;
 
-        Ok(LdapAddRequest { dn, attributes })
+        Ok(LdapModify {
+            operation,
+            modification,
+        })
+    }
+}
+
+impl TryFrom<Vec<StructureTag>> for LdapModifyRequest {
+    type Error = LdapProtoError;
+
+    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        // https://tools.ietf.org/html/rfc4511#section-4.6
+        value.reverse();
+
+        let dn = decode_field(value.pop(), "ModifyRequest", "object", decode_string)?;
+
+        let changes = decode_field(value.pop(), "ModifyRequest", "changes", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?
+        .into_iter()
+        .map(LdapModify::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(LdapModifyRequest { dn, changes })
     }
 }
 
@@ -1418,6 +2567,158 @@ impl From<LdapModifyRequest> for Vec<Tag> {
     }
 }
 
+impl TryFrom<Vec<StructureTag>> for LdapModifyDNRequest {
+    type Error = LdapProtoError;
+
+    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        // https://tools.ietf.org/html/rfc4511#section-4.9
+        value.reverse();
+
+        let dn = decode_field(value.pop(), "ModifyDNRequest", "entry", decode_string)?;
+
+        let newrdn = decode_field(value.pop(), "ModifyDNRequest", "newrdn", decode_string)?;
+
+        let deleteoldrdn = decode_field(value.pop(), "ModifyDNRequest", "deleteoldrdn", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Boolean as u64))
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_bool_to_bool)
+        })?;
+
+        // This is optional, so if it's absent that's fine, but if it's
+        // present it must be valid.
+        let new_superior = match value.pop() {
+            Some(t) => Some(decode_field(
+                Some(t),
+                "ModifyDNRequest",
+                "newSuperior",
+                |t| {
+                    t.match_class(TagClass::Context)
+                        .and_then(|t| t.match_id(0))
+                        .and_then(|t| t.expect_primitive())
+                        .and_then(|bv| String::from_utf8(bv).ok())
+                },
+            )?),
+            None => None,
+        };
+
+        Ok(LdapModifyDNRequest {
+            dn,
+            newrdn,
+            deleteoldrdn,
+            new_superior,
+        })
+    }
+}
+
+impl From<LdapModifyDNRequest> for Vec<Tag> {
+    fn from(value: LdapModifyDNRequest) -> Vec<Tag> {
+        let LdapModifyDNRequest {
+            dn,
+            newrdn,
+            deleteoldrdn,
+            new_superior,
+        } = value;
+
+        once_with(|| {
+            Some(Tag::OctetString(OctetString {
+                inner: Vec::from(dn),
+                ..Default::default()
+            }))
+        })
+        .chain(once_with(|| {
+            Some(Tag::OctetString(OctetString {
+                inner: Vec::from(newrdn),
+                ..Default::default()
+            }))
+        }))
+        .chain(once_with(|| {
+            Some(Tag::Boolean(Boolean {
+                inner: deleteoldrdn,
+                ..Default::default()
+            }))
+        }))
+        .chain(once_with(|| {
+            new_superior.map(|ns| {
+                Tag::OctetString(OctetString {
+                    id: 0,
+                    class: TagClass::Context,
+                    inner: Vec::from(ns),
+                })
+            })
+        }))
+        .flatten()
+        .collect()
+    }
+}
+
+impl LdapCompareRequest {
+    /// The assertion value as utf8 text.
+    pub fn val_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.val).ok()
+    }
+}
+
+impl TryFrom<Vec<StructureTag>> for LdapCompareRequest {
+    type Error = LdapProtoError;
+
+    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        // https://tools.ietf.org/html/rfc4511#section-4.10
+        value.reverse();
+
+        let dn = decode_field(value.pop(), "CompareRequest", "entry", decode_string)?;
+
+        // The AttributeValueAssertion
+        let mut ava = decode_field(value.pop(), "CompareRequest", "ava", |t| {
+            t.match_class(TagClass::Universal)
+                .and_then(|t| t.match_id(Types::Sequence as u64))
+                .and_then(|t| t.expect_constructed())
+        })?;
+        ava.reverse();
+
+        let atype = decode_field(
+            ava.pop(),
+            "AttributeValueAssertion",
+            "attributeDesc",
+            decode_string,
+        )?;
+
+        let val = decode_field(
+            ava.pop(),
+            "AttributeValueAssertion",
+            "assertionValue",
+            decode_octets,
+        )?;
+
+        Ok(LdapCompareRequest { dn, atype, val })
+    }
+}
+
+impl From<LdapCompareRequest> for Vec<Tag> {
+    fn from(value: LdapCompareRequest) -> Vec<Tag> {
+        let LdapCompareRequest { dn, atype, val } = value;
+        vec![
+            Tag::OctetString(OctetString {
+                inner: Vec::from(dn),
+                ..Default::default()
+            }),
+            Tag::Sequence(Sequence {
+                inner: vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(atype),
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: val,
+                        ..Default::default()
+                    }),
+                ],
+                ..Default::default()
+            }),
+        ]
+    }
+}
+
 impl From<LdapAddRequest> for Vec<Tag> {
     fn from(value: LdapAddRequest) -> Vec<Tag> {
         let LdapAddRequest { dn, attributes } = value;
@@ -1434,63 +2735,133 @@ impl From<LdapAddRequest> for Vec<Tag> {
     }
 }
 
-impl TryFrom<i64> for LdapResultCode {
+impl TryFrom<i64> for LdapModifyType {
     type Error = ();
 
     fn try_from(value: i64) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(LdapResultCode::Success),
-            1 => Ok(LdapResultCode::OperationsError),
-            2 => Ok(LdapResultCode::ProtocolError),
-            3 => Ok(LdapResultCode::TimeLimitExceeded),
-            4 => Ok(LdapResultCode::SizeLimitExceeded),
-            5 => Ok(LdapResultCode::CompareFalse),
-            6 => Ok(LdapResultCode::CompareTrue),
-            7 => Ok(LdapResultCode::AuthMethodNotSupported),
-            8 => Ok(LdapResultCode::StrongerAuthRequired),
-            10 => Ok(LdapResultCode::Referral),
-            11 => Ok(LdapResultCode::AdminLimitExceeded),
-            12 => Ok(LdapResultCode::UnavailableCriticalExtension),
-            13 => Ok(LdapResultCode::ConfidentialityRequired),
-            14 => Ok(LdapResultCode::SaslBindInProgress),
-            16 => Ok(LdapResultCode::NoSuchAttribute),
-            17 => Ok(LdapResultCode::UndefinedAttributeType),
-            18 => Ok(LdapResultCode::InappropriateMatching),
-            19 => Ok(LdapResultCode::ConstraintViolation),
-            20 => Ok(LdapResultCode::AttributeOrValueExists),
-            21 => Ok(LdapResultCode::InvalidAttributeSyntax),
-            32 => Ok(LdapResultCode::NoSuchObject),
-            33 => Ok(LdapResultCode::AliasProblem),
-            34 => Ok(LdapResultCode::InvalidDNSyntax),
-            36 => Ok(LdapResultCode::AliasDereferencingProblem),
-            48 => Ok(LdapResultCode::InappropriateAuthentication),
-            49 => Ok(LdapResultCode::InvalidCredentials),
-            50 => Ok(LdapResultCode::InsufficentAccessRights),
-            51 => Ok(LdapResultCode::Busy),
-            52 => Ok(LdapResultCode::Unavailable),
-            53 => Ok(LdapResultCode::UnwillingToPerform),
-            54 => Ok(LdapResultCode::LoopDetect),
-            64 => Ok(LdapResultCode::NamingViolation),
-            65 => Ok(LdapResultCode::ObjectClassViolation),
-            66 => Ok(LdapResultCode::NotAllowedOnNonLeaf),
-            67 => Ok(LdapResultCode::NotALlowedOnRDN),
-            68 => Ok(LdapResultCode::EntryAlreadyExists),
-            69 => Ok(LdapResultCode::ObjectClassModsProhibited),
-            71 => Ok(LdapResultCode::AffectsMultipleDSAs),
-            80 => Ok(LdapResultCode::Other),
+            0 => Ok(LdapModifyType::Add),
+            1 => Ok(LdapModifyType::Delete),
+            2 => Ok(LdapModifyType::Replace),
             _ => Err(()),
         }
     }
 }
 
-fn ber_bool_to_bool(bv: Vec<u8>) -> Option<bool> {
-    bv.get(0).map(|v| match v {
-        0 => false,
-        _ => true,
-    })
+impl From<i64> for LdapResultCode {
+    fn from(value: i64) -> Self {
+        match value {
+            0 => LdapResultCode::Success,
+            1 => LdapResultCode::OperationsError,
+            2 => LdapResultCode::ProtocolError,
+            3 => LdapResultCode::TimeLimitExceeded,
+            4 => LdapResultCode::SizeLimitExceeded,
+            5 => LdapResultCode::CompareFalse,
+            6 => LdapResultCode::CompareTrue,
+            7 => LdapResultCode::AuthMethodNotSupported,
+            8 => LdapResultCode::StrongerAuthRequired,
+            10 => LdapResultCode::Referral,
+            11 => LdapResultCode::AdminLimitExceeded,
+            12 => LdapResultCode::UnavailableCriticalExtension,
+            13 => LdapResultCode::ConfidentialityRequired,
+            14 => LdapResultCode::SaslBindInProgress,
+            16 => LdapResultCode::NoSuchAttribute,
+            17 => LdapResultCode::UndefinedAttributeType,
+            18 => LdapResultCode::InappropriateMatching,
+            19 => LdapResultCode::ConstraintViolation,
+            20 => LdapResultCode::AttributeOrValueExists,
+            21 => LdapResultCode::InvalidAttributeSyntax,
+            32 => LdapResultCode::NoSuchObject,
+            33 => LdapResultCode::AliasProblem,
+            34 => LdapResultCode::InvalidDNSyntax,
+            36 => LdapResultCode::AliasDereferencingProblem,
+            48 => LdapResultCode::InappropriateAuthentication,
+            49 => LdapResultCode::InvalidCredentials,
+            50 => LdapResultCode::InsufficentAccessRights,
+            51 => LdapResultCode::Busy,
+            52 => LdapResultCode::Unavailable,
+            53 => LdapResultCode::UnwillingToPerform,
+            54 => LdapResultCode::LoopDetect,
+            64 => LdapResultCode::NamingViolation,
+            65 => LdapResultCode::ObjectClassViolation,
+            66 => LdapResultCode::NotAllowedOnNonLeaf,
+            67 => LdapResultCode::NotALlowedOnRDN,
+            68 => LdapResultCode::EntryAlreadyExists,
+            69 => LdapResultCode::ObjectClassModsProhibited,
+            71 => LdapResultCode::AffectsMultipleDSAs,
+            80 => LdapResultCode::Other,
+            118 => LdapResultCode::Canceled,
+            119 => LdapResultCode::NoSuchOperation,
+            120 => LdapResultCode::TooLate,
+            121 => LdapResultCode::CannotCancel,
+            122 => LdapResultCode::AssertionFailed,
+            123 => LdapResultCode::AuthorizationDenied,
+            4096 => LdapResultCode::ESyncRefreshRequired,
+            v => LdapResultCode::Unknown(v),
+        }
+    }
+}
+
+impl From<&LdapResultCode> for i64 {
+    fn from(value: &LdapResultCode) -> i64 {
+        match value {
+            LdapResultCode::Success => 0,
+            LdapResultCode::OperationsError => 1,
+            LdapResultCode::ProtocolError => 2,
+            LdapResultCode::TimeLimitExceeded => 3,
+            LdapResultCode::SizeLimitExceeded => 4,
+            LdapResultCode::CompareFalse => 5,
+            LdapResultCode::CompareTrue => 6,
+            LdapResultCode::AuthMethodNotSupported => 7,
+            LdapResultCode::StrongerAuthRequired => 8,
+            LdapResultCode::Referral => 10,
+            LdapResultCode::AdminLimitExceeded => 11,
+            LdapResultCode::UnavailableCriticalExtension => 12,
+            LdapResultCode::ConfidentialityRequired => 13,
+            LdapResultCode::SaslBindInProgress => 14,
+            LdapResultCode::NoSuchAttribute => 16,
+            LdapResultCode::UndefinedAttributeType => 17,
+            LdapResultCode::InappropriateMatching => 18,
+            LdapResultCode::ConstraintViolation => 19,
+            LdapResultCode::AttributeOrValueExists => 20,
+            LdapResultCode::InvalidAttributeSyntax => 21,
+            LdapResultCode::NoSuchObject => 32,
+            LdapResultCode::AliasProblem => 33,
+            LdapResultCode::InvalidDNSyntax => 34,
+            LdapResultCode::AliasDereferencingProblem => 36,
+            LdapResultCode::InappropriateAuthentication => 48,
+            LdapResultCode::InvalidCredentials => 49,
+            LdapResultCode::InsufficentAccessRights => 50,
+            LdapResultCode::Busy => 51,
+            LdapResultCode::Unavailable => 52,
+            LdapResultCode::UnwillingToPerform => 53,
+            LdapResultCode::LoopDetect => 54,
+            LdapResultCode::NamingViolation => 64,
+            LdapResultCode::ObjectClassViolation => 65,
+            LdapResultCode::NotAllowedOnNonLeaf => 66,
+            LdapResultCode::NotALlowedOnRDN => 67,
+            LdapResultCode::EntryAlreadyExists => 68,
+            LdapResultCode::ObjectClassModsProhibited => 69,
+            LdapResultCode::AffectsMultipleDSAs => 71,
+            LdapResultCode::Other => 80,
+            LdapResultCode::Canceled => 118,
+            LdapResultCode::NoSuchOperation => 119,
+            LdapResultCode::TooLate => 120,
+            LdapResultCode::CannotCancel => 121,
+            LdapResultCode::AssertionFailed => 122,
+            LdapResultCode::AuthorizationDenied => 123,
+            LdapResultCode::ESyncRefreshRequired => 4096,
+            LdapResultCode::Unknown(v) => *v,
+        }
+    }
+}
+
+pub(crate) fn ber_bool_to_bool<T: AsRef<[u8]>>(bv: T) -> Option<bool> {
+    bv.as_ref().first().map(|v| *v != 0)
 }
 
-fn ber_integer_to_i64(bv: Vec<u8>) -> Option<i64> {
+pub(crate) fn ber_integer_to_i64<T: AsRef<[u8]>>(bv: T) -> Option<i64> {
+    let bv = bv.as_ref();
     // ints in ber are be and may be truncated.
     let mut raw: [u8; 8] = [0; 8];
     // This is where we need to start inserting bytes.
@@ -1499,8 +2870,10 @@ fn ber_integer_to_i64(bv: Vec<u8>) -> Option<i64> {
     } else {
         8 - bv.len()
     };
-    for i in 0..bv.len() {
-        raw[base + i] = bv[i];
+    raw[base..].copy_from_slice(bv);
+    // Sign extend negative values.
+    if bv.first().is_some_and(|b| b & 0x80 != 0) {
+        raw[..base].fill(0xff);
     }
     Some(i64::from_be_bytes(raw))
 }
@@ -0,0 +1,495 @@
+// https://tools.ietf.org/html/rfc5802
+// https://tools.ietf.org/html/rfc7677
+//
+// A server side SCRAM-SHA-256 exchange. Channel binding is not supported as
+// we don't have access to the tls layer here, so only the "n" and "y" gs2
+// headers are accepted. The password is used as given - if you need SASLprep
+// apply it before deriving the credentials.
+
+use crate::proto::LdapResultCode;
+use crate::simple::{SaslBindRequest, SaslCredentials};
+use crate::LdapMsg;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+const NONCE_LEN: usize = 18;
+const KEY_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScramError {
+    InvalidEncoding,
+    ExtensionsNotSupported,
+    InvalidProof,
+    ChannelBindingsDontMatch,
+    ChannelBindingNotSupported,
+    InvalidUsernameEncoding,
+    InvalidNonce,
+    WrongMechanism,
+}
+
+impl ScramError {
+    // https://tools.ietf.org/html/rfc5802#section-7
+    pub fn server_error_value(&self) -> &'static str {
+        match self {
+            ScramError::InvalidEncoding | ScramError::InvalidNonce => "invalid-encoding",
+            ScramError::ExtensionsNotSupported => "extensions-not-supported",
+            ScramError::InvalidProof => "invalid-proof",
+            ScramError::ChannelBindingsDontMatch => "channel-bindings-dont-match",
+            ScramError::ChannelBindingNotSupported => "channel-binding-not-supported",
+            ScramError::InvalidUsernameEncoding => "invalid-username-encoding",
+            ScramError::WrongMechanism => "other-error",
+        }
+    }
+}
+
+/// The values a server stores for a user to allow SCRAM authentication,
+/// without needing to keep the password itself. The keys are as good as a
+/// password for this server, so they are left out of the Debug output.
+#[derive(Clone, PartialEq)]
+pub struct ScramSha256Credentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: [u8; KEY_LEN],
+    pub server_key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for ScramSha256Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256Credentials")
+            .field("salt", &self.salt)
+            .field("iterations", &self.iterations)
+            .field("stored_key", &"<redacted>")
+            .field("server_key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl ScramSha256Credentials {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0; KEY_LEN];
+        pbkdf2::pbkdf2::<HmacSha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        let mut stored_key = [0; KEY_LEN];
+        stored_key.copy_from_slice(&Sha256::digest(&client_key));
+
+        ScramSha256Credentials {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key,
+            server_key,
+        }
+    }
+
+    /// Derive credentials with a new random salt.
+    pub fn generate(password: &str, iterations: u32) -> Self {
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::new(password, &salt, iterations)
+    }
+}
+
+/// The parsed client-first-message. Use the authcid to find the users
+/// credentials, then call `begin` to continue the exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramSha256ClientFirst {
+    pub authcid: String,
+    pub authzid: Option<String>,
+    gs2_header: String,
+    client_first_bare: String,
+    client_nonce: String,
+}
+
+/// The server is waiting for the client-final-message.
+#[derive(Clone, PartialEq)]
+pub struct ScramSha256ServerFirst {
+    pub authcid: String,
+    pub authzid: Option<String>,
+    creds: ScramSha256Credentials,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl fmt::Debug for ScramSha256ServerFirst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256ServerFirst")
+            .field("authcid", &self.authcid)
+            .field("authzid", &self.authzid)
+            .field("nonce", &self.nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScramSha256ClientFirst {
+    pub fn parse(data: &[u8]) -> Result<Self, ScramError> {
+        let msg = std::str::from_utf8(data).map_err(|_| ScramError::InvalidEncoding)?;
+
+        // gs2-header = gs2-cbind-flag "," [ authzid ] ","
+        let mut parts = msg.splitn(3, ',');
+        let cbind = parts.next().ok_or(ScramError::InvalidEncoding)?;
+        let authzid = parts.next().ok_or(ScramError::InvalidEncoding)?;
+        let client_first_bare = parts.next().ok_or(ScramError::InvalidEncoding)?;
+
+        match cbind {
+            "n" | "y" => {}
+            c if c.starts_with("p=") => return Err(ScramError::ChannelBindingNotSupported),
+            _ => return Err(ScramError::InvalidEncoding),
+        }
+
+        let authzid = match authzid {
+            "" => None,
+            a => Some(
+                a.strip_prefix("a=")
+                    .ok_or(ScramError::InvalidEncoding)
+                    .and_then(decode_saslname)?,
+            ),
+        };
+
+        // client-first-message-bare = [reserved-mext ","] username "," nonce ["," extensions]
+        let mut attrs = client_first_bare.split(',');
+        let authcid = match attrs.next() {
+            Some(m) if m.starts_with("m=") => return Err(ScramError::ExtensionsNotSupported),
+            Some(n) => n
+                .strip_prefix("n=")
+                .ok_or(ScramError::InvalidEncoding)
+                .and_then(decode_saslname)?,
+            None => return Err(ScramError::InvalidEncoding),
+        };
+
+        let client_nonce = attrs
+            .next()
+            .and_then(|r| r.strip_prefix("r="))
+            .filter(|r| valid_nonce(r))
+            .ok_or(ScramError::InvalidNonce)?
+            .to_string();
+
+        Ok(ScramSha256ClientFirst {
+            authcid,
+            authzid,
+            gs2_header: msg[..msg.len() - client_first_bare.len()].to_string(),
+            client_first_bare: client_first_bare.to_string(),
+            client_nonce,
+        })
+    }
+
+    pub fn begin(self, creds: ScramSha256Credentials) -> ScramSha256ServerFirst {
+        let mut raw = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut raw);
+        self.begin_with_nonce(creds, &base64::encode(raw))
+    }
+
+    fn begin_with_nonce(
+        self,
+        creds: ScramSha256Credentials,
+        server_nonce: &str,
+    ) -> ScramSha256ServerFirst {
+        let ScramSha256ClientFirst {
+            authcid,
+            authzid,
+            gs2_header,
+            client_first_bare,
+            client_nonce,
+        } = self;
+
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            base64::encode(&creds.salt),
+            creds.iterations
+        );
+
+        ScramSha256ServerFirst {
+            authcid,
+            authzid,
+            creds,
+            gs2_header,
+            client_first_bare,
+            server_first,
+            nonce,
+        }
+    }
+}
+
+impl ScramSha256ServerFirst {
+    /// The server-first-message to send to the client.
+    pub fn message(&self) -> Vec<u8> {
+        Vec::from(self.server_first.as_str())
+    }
+
+    /// Verify the client-final-message, returning the server-final-message
+    /// on success.
+    pub fn finish(&self, data: &[u8]) -> Result<Vec<u8>, ScramError> {
+        let msg = std::str::from_utf8(data).map_err(|_| ScramError::InvalidEncoding)?;
+
+        // client-final-message = channel-binding "," nonce ["," extensions] "," proof
+        let (without_proof, proof) = msg.rsplit_once(',').ok_or(ScramError::InvalidEncoding)?;
+        let proof = proof
+            .strip_prefix("p=")
+            .and_then(|p| base64::decode(p).ok())
+            .filter(|p| p.len() == KEY_LEN)
+            .ok_or(ScramError::InvalidEncoding)?;
+
+        let mut attrs = without_proof.split(',');
+        let cbind = attrs
+            .next()
+            .and_then(|c| c.strip_prefix("c="))
+            .and_then(|c| base64::decode(c).ok())
+            .ok_or(ScramError::InvalidEncoding)?;
+        if cbind != self.gs2_header.as_bytes() {
+            return Err(ScramError::ChannelBindingsDontMatch);
+        }
+
+        let nonce = attrs
+            .next()
+            .and_then(|r| r.strip_prefix("r="))
+            .ok_or(ScramError::InvalidEncoding)?;
+        if nonce != self.nonce {
+            return Err(ScramError::InvalidNonce);
+        }
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        // ClientKey := ClientProof XOR ClientSignature, and it's valid if
+        // H(ClientKey) is our StoredKey.
+        let client_signature = hmac_sha256(&self.creds.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(p, s)| p ^ s)
+            .collect();
+
+        // Don't short circuit the comparison, so we don't leak timing.
+        let diff = Sha256::digest(&client_key)
+            .iter()
+            .zip(self.creds.stored_key.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(ScramError::InvalidProof);
+        }
+
+        let server_signature = hmac_sha256(&self.creds.server_key, auth_message.as_bytes());
+        Ok(Vec::from(format!("v={}", base64::encode(server_signature))))
+    }
+
+    /// Reply to the bind carrying the client-first-message with our challenge.
+    pub fn gen_in_progress(&self, sbr: &SaslBindRequest) -> LdapMsg {
+        sbr.gen_in_progress(Some(self.message()))
+    }
+
+    /// Reply to the bind carrying the client-final-message. On failure the
+    /// server-error is sent back to the client with invalidCredentials.
+    pub fn gen_final(&self, sbr: &SaslBindRequest) -> LdapMsg {
+        let result = match &sbr.creds {
+            SaslCredentials::Other {
+                mechanism,
+                credentials: Some(c),
+            } if mechanism == SCRAM_SHA_256 => self.finish(c),
+            _ => Err(ScramError::WrongMechanism),
+        };
+
+        match result {
+            Ok(server_final) => sbr.gen_success(Some(server_final)),
+            Err(e) => sbr.gen_response(
+                LdapResultCode::InvalidCredentials,
+                "",
+                Some(Vec::from(format!("e={}", e.server_error_value()))),
+            ),
+        }
+    }
+}
+
+impl ScramSha256ClientFirst {
+    /// Parse the client-first-message from the first bind of the exchange.
+    pub fn from_bind(sbr: &SaslBindRequest) -> Result<Self, ScramError> {
+        match &sbr.creds {
+            SaslCredentials::Other {
+                mechanism,
+                credentials: Some(c),
+            } if mechanism == SCRAM_SHA_256 => Self::parse(c),
+            _ => Err(ScramError::WrongMechanism),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(data);
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+// printable = %x21-2B / %x2D-7E, ie everything printable except ","
+fn valid_nonce(nonce: &str) -> bool {
+    !nonce.is_empty()
+        && nonce
+            .bytes()
+            .all(|b| (0x21..=0x7e).contains(&b) && b != b',')
+}
+
+// "=2C" and "=3D" are the only escapes allowed in a saslname.
+fn decode_saslname(name: &str) -> Result<String, ScramError> {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(idx) = rest.find('=') {
+        out.push_str(&rest[..idx]);
+        match rest.get(idx..idx + 3) {
+            Some("=2C") => out.push(','),
+            Some("=3D") => out.push('='),
+            _ => return Err(ScramError::InvalidUsernameEncoding),
+        }
+        rest = &rest[idx + 3..];
+    }
+    out.push_str(rest);
+    if out.is_empty() {
+        Err(ScramError::InvalidUsernameEncoding)
+    } else {
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://tools.ietf.org/html/rfc7677#section-3
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    // The rfc has a typo in this value, this is the corrected one from the errata.
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    fn rfc_server_first() -> ScramSha256ServerFirst {
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let creds = ScramSha256Credentials::new("pencil", &salt, 4096);
+        let cf = ScramSha256ClientFirst::parse(CLIENT_FIRST.as_bytes()).expect("client first");
+        assert!(cf.authcid == "user");
+        assert!(cf.authzid.is_none());
+        cf.begin_with_nonce(creds, SERVER_NONCE)
+    }
+
+    #[test]
+    fn test_scram_sha256_rfc7677() {
+        let sf = rfc_server_first();
+        assert!(sf.message() == SERVER_FIRST.as_bytes());
+        let server_final = sf.finish(CLIENT_FINAL.as_bytes()).expect("client final");
+        assert!(server_final == SERVER_FINAL.as_bytes());
+    }
+
+    #[test]
+    fn test_scram_sha256_debug_redacted() {
+        let sf = rfc_server_first();
+        let stored_key = format!("{:?}", sf.creds.stored_key);
+        let server_key = format!("{:?}", sf.creds.server_key);
+        assert!(format!("{:?}", sf.creds).contains("<redacted>"));
+        for s in [format!("{:?}", sf.creds), format!("{:?}", sf)].iter() {
+            assert!(!s.contains(&stored_key));
+            assert!(!s.contains(&server_key));
+        }
+    }
+
+    #[test]
+    fn test_scram_sha256_invalid() {
+        let sf = rfc_server_first();
+        // Wrong password
+        let bad_proof = CLIENT_FINAL.replace("p=dHzb", "p=dHzc");
+        assert!(sf.finish(bad_proof.as_bytes()) == Err(ScramError::InvalidProof));
+        // Wrong nonce
+        let bad_nonce = CLIENT_FINAL.replace("hNlF$k0", "hNlF$k1");
+        assert!(sf.finish(bad_nonce.as_bytes()) == Err(ScramError::InvalidNonce));
+        // Changed gs2 header
+        let bad_cbind = CLIENT_FINAL.replace("c=biws", "c=eSws");
+        assert!(sf.finish(bad_cbind.as_bytes()) == Err(ScramError::ChannelBindingsDontMatch));
+
+        assert!(
+            ScramSha256ClientFirst::parse(b"p=tls-unique,,n=user,r=abc")
+                == Err(ScramError::ChannelBindingNotSupported)
+        );
+        assert!(
+            ScramSha256ClientFirst::parse(b"n,,n=us=er,r=abc")
+                == Err(ScramError::InvalidUsernameEncoding)
+        );
+        assert!(ScramSha256ClientFirst::parse(b"n,,n=user").is_err());
+
+        let cf = ScramSha256ClientFirst::parse(b"y,a=ad=2Cmin,n=us=3Der,r=abc").unwrap();
+        assert!(cf.authzid.as_deref() == Some("ad,min"));
+        assert!(cf.authcid == "us=er");
+    }
+
+    #[test]
+    fn test_scram_sha256_bind() {
+        use crate::proto::*;
+        use crate::simple::ServerOps;
+        use std::convert::TryFrom;
+
+        let bind = |msgid, data: &str| match ServerOps::try_from(LdapMsg {
+            msgid,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
+                dn: "".to_string(),
+                cred: LdapBindCred::Sasl {
+                    mechanism: SCRAM_SHA_256.to_string(),
+                    credentials: Some(Vec::from(data)),
+                },
+            }),
+            ctrl: vec![],
+        }) {
+            Ok(ServerOps::SaslBind(sbr)) => sbr,
+            _ => panic!("not a sasl bind"),
+        };
+
+        let sbr = bind(1, CLIENT_FIRST);
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let sf = ScramSha256ClientFirst::from_bind(&sbr)
+            .expect("client first")
+            .begin_with_nonce(
+                ScramSha256Credentials::new("pencil", &salt, 4096),
+                SERVER_NONCE,
+            );
+
+        match sf.gen_in_progress(&sbr).op {
+            LdapOp::BindResponse(LdapBindResponse { res, saslcreds }) => {
+                assert!(res.code == LdapResultCode::SaslBindInProgress);
+                assert!(saslcreds.as_deref() == Some(SERVER_FIRST.as_bytes()));
+            }
+            _ => panic!("not a bind response"),
+        }
+
+        match sf.gen_final(&bind(2, CLIENT_FINAL)) {
+            LdapMsg {
+                msgid: 2,
+                op: LdapOp::BindResponse(LdapBindResponse { res, saslcreds }),
+                ..
+            } => {
+                assert!(res.code == LdapResultCode::Success);
+                assert!(saslcreds.as_deref() == Some(SERVER_FINAL.as_bytes()));
+            }
+            _ => panic!("not a bind response"),
+        }
+
+        let bad_proof = CLIENT_FINAL.replace("p=dHzb", "p=dHzc");
+        match sf.gen_final(&bind(3, &bad_proof)).op {
+            LdapOp::BindResponse(LdapBindResponse { res, saslcreds }) => {
+                assert!(res.code == LdapResultCode::InvalidCredentials);
+                assert!(saslcreds.as_deref() == Some("e=invalid-proof".as_bytes()));
+            }
+            _ => panic!("not a bind response"),
+        }
+    }
+}
@@ -1,9 +1,15 @@
+use crate::encode::BerEncode;
 use crate::proto::*;
 pub use crate::proto::{
-    LdapFilter, LdapMsg, LdapPartialAttribute, LdapResultCode, LdapSearchResultEntry,
-    LdapSearchScope,
+    LdapControl, LdapFilter, LdapMalformedMsg, LdapMsg, LdapPagedResults, LdapPartialAttribute,
+    LdapResultCode, LdapSearchResultEntry, LdapSearchScope,
 };
+use bytes::BytesMut;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
+use std::iter::once;
 
 pub struct SearchRequest {
     pub msgid: i32,
@@ -11,14 +17,44 @@ pub struct SearchRequest {
     pub scope: LdapSearchScope,
     pub filter: LdapFilter,
     pub attrs: Vec<String>,
+    /// The controls sent with the search, such as `LdapPagedResults`.
+    pub ctrl: Vec<LdapControl>,
 }
 
 pub struct SimpleBindRequest {
     pub msgid: i32,
+    /// The protocol version. Binds with any version other than 3 are
+    /// refused before they get here, see `ServerOps::UnsupportedVersion`.
+    pub version: i32,
     pub dn: String,
     pub pw: String,
 }
 
+// https://tools.ietf.org/html/rfc4422
+pub enum SaslCredentials {
+    // https://tools.ietf.org/html/rfc4422#appendix-A
+    External {
+        authzid: Option<String>,
+    },
+    // https://tools.ietf.org/html/rfc4616
+    Plain {
+        authzid: Option<String>,
+        authcid: String,
+        password: String,
+    },
+    // Any other mechanism, or a later step of a multi-step exchange.
+    Other {
+        mechanism: String,
+        credentials: Option<Vec<u8>>,
+    },
+}
+
+pub struct SaslBindRequest {
+    pub msgid: i32,
+    pub dn: String,
+    pub creds: SaslCredentials,
+}
+
 pub struct UnbindRequest;
 
 pub struct WhoamiRequest {
@@ -27,23 +63,168 @@ pub struct WhoamiRequest {
 
 pub struct DisconnectionNotice;
 
+/// The set of control oids that a server understands. If a request carries
+/// a critical control that is not in this set, the request must not be
+/// performed and unavailableCriticalExtension is returned instead.
+/// https://tools.ietf.org/html/rfc4511#section-4.1.11
+#[derive(Debug, Clone, Default)]
+pub struct SupportedControls {
+    oids: BTreeSet<String>,
+}
+
 pub enum ServerOps {
     Search(SearchRequest),
     SimpleBind(SimpleBindRequest),
+    SaslBind(SaslBindRequest),
     Unbind(UnbindRequest),
     Whoami(WhoamiRequest),
+    /// The request carried a critical control we don't support. This
+    /// contains the error response that should be sent to the client.
+    UnavailableCriticalExtension(LdapMsg),
+    /// An unbind or abandon carrying a critical control we don't support.
+    /// These have no response, so the request must simply be ignored: the
+    /// connection stays open and nothing is abandoned. This contains the
+    /// original request.
+    DroppedCriticalExtension(LdapMsg),
+    /// A bind with a protocol version other than 3. This contains the
+    /// protocolError response that should be sent to the client.
+    /// https://tools.ietf.org/html/rfc4511#section-4.2
+    UnsupportedVersion(LdapMsg),
+    /// A SASL bind whose credentials are malformed for its mechanism. This
+    /// contains the invalidCredentials response that should be sent to the
+    /// client, so the connection can carry on.
+    InvalidSaslCredentials(LdapMsg),
 }
 
-impl TryFrom<LdapMsg> for ServerOps {
-    type Error = ();
+impl SupportedControls {
+    pub fn new() -> Self {
+        SupportedControls::default()
+    }
 
-    fn try_from(value: LdapMsg) -> Result<Self, Self::Error> {
-        let LdapMsg { msgid, op, ctrl: _ } = value;
+    pub fn register(&mut self, oid: &str) {
+        self.oids.insert(oid.to_string());
+    }
+
+    pub fn is_supported(&self, oid: &str) -> bool {
+        self.oids.contains(oid)
+    }
+
+    /// Find the first critical control of a request that we don't support.
+    pub fn unsupported_critical<'a>(&self, msg: &'a LdapMsg) -> Option<&'a LdapControl> {
+        msg.ctrl
+            .iter()
+            .find(|c| c.criticality && !self.is_supported(c.oid.as_str()))
+    }
+
+    /// Check the controls of a request, returning the error response to send
+    /// if there is a critical control we don't support. Unbind and abandon
+    /// have no response, so this returns None for them even though they must
+    /// not be performed - use `unsupported_critical` to detect that case.
+    pub fn check(&self, msg: &LdapMsg) -> Option<LdapMsg> {
+        let unsupported = self.unsupported_critical(msg)?;
+
+        let res = LdapResult {
+            code: LdapResultCode::UnavailableCriticalExtension,
+            matcheddn: "".to_string(),
+            message: format!("unsupported critical control {}", unsupported.oid),
+            referral: vec![],
+        };
+
+        let op = match &msg.op {
+            LdapOp::BindRequest(_) => LdapOp::BindResponse(LdapBindResponse {
+                res,
+                saslcreds: None,
+            }),
+            LdapOp::SearchRequest(_) => LdapOp::SearchResultDone(res),
+            LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse(res),
+            LdapOp::AddRequest(_) => LdapOp::AddResponse(res),
+            LdapOp::DelRequest(_) => LdapOp::DelResponse(res),
+            LdapOp::ModifyDNRequest(_) => LdapOp::ModifyDNResponse(res),
+            LdapOp::CompareRequest(_) => LdapOp::CompareResponse(res),
+            LdapOp::ExtendedRequest(_) => LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res,
+                name: None,
+                value: None,
+            }),
+            _ => return None,
+        };
+
+        Some(LdapMsg {
+            msgid: msg.msgid,
+            op,
+            ctrl: vec![],
+        })
+    }
+}
+
+impl ServerOps {
+    /// Convert a message to a server operation, rejecting any critical
+    /// controls that are not in `controls`. An unbind or abandon with such
+    /// a control becomes `DroppedCriticalExtension`, since there is no
+    /// response to send for them.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_from_with_controls(
+        value: LdapMsg,
+        controls: &SupportedControls,
+    ) -> Result<Self, ()> {
+        if controls.unsupported_critical(&value).is_some() {
+            return Ok(match controls.check(&value) {
+                Some(rmsg) => ServerOps::UnavailableCriticalExtension(rmsg),
+                None => ServerOps::DroppedCriticalExtension(value),
+            });
+        }
+
+        let LdapMsg { msgid, op, ctrl } = value;
         match op {
+            LdapOp::BindRequest(LdapBindRequest { version, .. }) if version != 3 => {
+                Ok(ServerOps::UnsupportedVersion(LdapMsg {
+                    msgid,
+                    op: LdapOp::BindResponse(LdapBindResponse {
+                        res: LdapResult {
+                            code: LdapResultCode::ProtocolError,
+                            matcheddn: "".to_string(),
+                            message: format!("unsupported protocol version {}", version),
+                            referral: vec![],
+                        },
+                        saslcreds: None,
+                    }),
+                    ctrl: vec![],
+                }))
+            }
             LdapOp::BindRequest(LdapBindRequest {
+                version,
                 dn,
                 cred: LdapBindCred::Simple(pw),
-            }) => Ok(ServerOps::SimpleBind(SimpleBindRequest { msgid, dn, pw })),
+            }) => Ok(ServerOps::SimpleBind(SimpleBindRequest {
+                msgid,
+                version,
+                dn,
+                pw,
+            })),
+            LdapOp::BindRequest(LdapBindRequest {
+                version: _,
+                dn,
+                cred:
+                    LdapBindCred::Sasl {
+                        mechanism,
+                        credentials,
+                    },
+            }) => match SaslCredentials::try_from((mechanism, credentials)) {
+                Ok(creds) => Ok(ServerOps::SaslBind(SaslBindRequest { msgid, dn, creds })),
+                Err(()) => Ok(ServerOps::InvalidSaslCredentials(LdapMsg {
+                    msgid,
+                    op: LdapOp::BindResponse(LdapBindResponse {
+                        res: LdapResult {
+                            code: LdapResultCode::InvalidCredentials,
+                            matcheddn: "".to_string(),
+                            message: "malformed sasl credentials".to_string(),
+                            referral: vec![],
+                        },
+                        saslcreds: None,
+                    }),
+                    ctrl: vec![],
+                })),
+            },
             LdapOp::UnbindRequest => Ok(ServerOps::Unbind(UnbindRequest)),
             LdapOp::SearchRequest(lsr) => {
                 let LdapSearchRequest {
@@ -62,6 +243,7 @@ impl TryFrom<LdapMsg> for ServerOps {
                     scope,
                     filter,
                     attrs,
+                    ctrl,
                 }))
             }
             LdapOp::ExtendedRequest(ler) => match ler.name.as_str() {
@@ -73,6 +255,64 @@ impl TryFrom<LdapMsg> for ServerOps {
     }
 }
 
+impl TryFrom<LdapMsg> for ServerOps {
+    type Error = ();
+
+    /// As no controls are supported by default, any request with a
+    /// critical control is rejected.
+    fn try_from(value: LdapMsg) -> Result<Self, Self::Error> {
+        ServerOps::try_from_with_controls(value, &SupportedControls::default())
+    }
+}
+
+impl TryFrom<(String, Option<Vec<u8>>)> for SaslCredentials {
+    type Error = ();
+
+    fn try_from(value: (String, Option<Vec<u8>>)) -> Result<Self, Self::Error> {
+        let (mechanism, credentials) = value;
+        match mechanism.as_str() {
+            "EXTERNAL" => {
+                // An empty or absent authzid means "derive it from the
+                // external credentials".
+                let authzid = match credentials {
+                    Some(c) if !c.is_empty() => Some(String::from_utf8(c).map_err(|_| ())?),
+                    _ => None,
+                };
+                Ok(SaslCredentials::External { authzid })
+            }
+            "PLAIN" => {
+                // message = [authzid] UTF8NUL authcid UTF8NUL passwd
+                let c = credentials.ok_or(())?;
+                let mut parts = c.split(|b| *b == 0);
+                let authzid = parts.next().ok_or(())?;
+                let authcid = parts.next().ok_or(())?;
+                let password = parts.next().ok_or(())?;
+                if parts.next().is_some() || authcid.is_empty() || password.is_empty() {
+                    return Err(());
+                }
+
+                let authzid = if authzid.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8(authzid.to_vec()).map_err(|_| ())?)
+                };
+                let authcid = String::from_utf8(authcid.to_vec()).map_err(|_| ())?;
+                let password = String::from_utf8(password.to_vec()).map_err(|_| ())?;
+
+                Ok(SaslCredentials::Plain {
+                    authzid,
+                    authcid,
+                    password,
+                })
+            }
+            _ => Ok(SaslCredentials::Other {
+                mechanism,
+                credentials,
+            }),
+        }
+    }
+}
+
 impl DisconnectionNotice {
     pub fn gen(code: LdapResultCode, msg: &str) -> LdapMsg {
         // name 1.3.6.1.4.1.1466.20036
@@ -103,6 +343,120 @@ impl SearchRequest {
         }
     }
 
+    pub fn gen_result_reference(&self, uris: Vec<String>) -> LdapMsg {
+        LdapMsg {
+            msgid: self.msgid,
+            op: LdapOp::SearchResultReference(uris),
+            ctrl: vec![],
+        }
+    }
+
+    /// Answer the search with `entries`, a page at a time if the client sent
+    /// the paged results control. The cookie we issue is the offset of the
+    /// next page, so `entries` must be the whole result set, in the same
+    /// order, each time the client asks for the next one. The offset is
+    /// tagged with an HMAC-SHA-256 of the search under `key`, so a cookie
+    /// that was altered, or that belongs to a different search, is refused.
+    ///
+    /// `key` should be a random secret of at least 32 bytes. Cookies are only
+    /// accepted by servers using the same key, so share it between instances
+    /// behind a load balancer, and keep it across restarts if paged searches
+    /// should carry on after one.
+    pub fn gen_paged(&self, key: &[u8], entries: Vec<LdapSearchResultEntry>) -> Vec<LdapMsg> {
+        let paged = match self
+            .ctrl
+            .iter()
+            .find(|c| c.oid == LdapPagedResults::OID)
+            .map(LdapPagedResults::try_from)
+        {
+            Some(Ok(paged)) => paged,
+            Some(Err(e)) => {
+                return vec![self.gen_error(LdapResultCode::ProtocolError, e.to_string())]
+            }
+            None => {
+                return entries
+                    .into_iter()
+                    .map(|e| self.gen_result_entry(e))
+                    .chain(once(self.gen_success()))
+                    .collect()
+            }
+        };
+
+        let total = entries.len();
+        let offset = if paged.cookie.is_empty() {
+            Some(0)
+        } else {
+            <[u8; 40]>::try_from(paged.cookie.as_slice())
+                .ok()
+                .and_then(|cookie| {
+                    let (offset, tag) = cookie.split_at(8);
+                    let offset = u64::from_be_bytes(<[u8; 8]>::try_from(offset).ok()?);
+                    let mac = self.paged_cookie_mac(key, offset);
+                    mac.verify(tag).ok().map(|_| offset)
+                })
+                .and_then(|o| usize::try_from(o).ok())
+                .filter(|o| *o <= total)
+        };
+        let offset = match offset {
+            Some(o) => o,
+            None => {
+                return vec![self.gen_error(
+                    LdapResultCode::UnwillingToPerform,
+                    "invalid paged results cookie".to_string(),
+                )]
+            }
+        };
+
+        let (end, cookie) = match paged.size as usize {
+            // A size of 0 abandons the paged search.
+            0 => (offset, Vec::new()),
+            size => {
+                let end = offset.saturating_add(size).min(total);
+                let cookie = if end < total {
+                    let mut cookie = (end as u64).to_be_bytes().to_vec();
+                    let mac = self.paged_cookie_mac(key, end as u64);
+                    cookie.extend_from_slice(&mac.finalize().into_bytes());
+                    cookie
+                } else {
+                    Vec::new()
+                };
+                (end, cookie)
+            }
+        };
+
+        let mut done = self.gen_success();
+        done.ctrl.push(
+            LdapPagedResults {
+                size: i32::try_from(total).unwrap_or(i32::MAX),
+                cookie,
+            }
+            .into(),
+        );
+
+        entries
+            .into_iter()
+            .skip(offset)
+            .take(end - offset)
+            .map(|e| self.gen_result_entry(e))
+            .chain(once(done))
+            .collect()
+    }
+
+    // Ties a paged results offset to this search. The base is length
+    // prefixed and the filter is BER, so no two searches share an input.
+    fn paged_cookie_mac(&self, key: &[u8], offset: u64) -> Hmac<Sha256> {
+        let mut filter = BytesMut::new();
+        self.filter.encode(&mut filter);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+        mac.update(&(self.base.len() as u64).to_be_bytes());
+        mac.update(self.base.as_bytes());
+        mac.update(&(self.scope.clone() as i64).to_be_bytes());
+        mac.update(&filter);
+        mac.update(&offset.to_be_bytes());
+        mac
+    }
+
     pub fn gen_success(&self) -> LdapMsg {
         LdapMsg {
             msgid: self.msgid,
@@ -141,6 +495,19 @@ impl SearchRequest {
             ctrl: vec![],
         }
     }
+
+    pub fn gen_referral(&self, urls: Vec<String>) -> LdapMsg {
+        LdapMsg {
+            msgid: self.msgid,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Referral,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: urls,
+            }),
+            ctrl: vec![],
+        }
+    }
 }
 
 impl SimpleBindRequest {
@@ -207,6 +574,79 @@ impl SimpleBindRequest {
             ctrl: vec![],
         }
     }
+
+    pub fn gen_referral(&self, urls: Vec<String>) -> LdapMsg {
+        LdapMsg {
+            msgid: self.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Referral,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: urls,
+                },
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }
+    }
+}
+
+impl SaslBindRequest {
+    pub(crate) fn gen_response(
+        &self,
+        code: LdapResultCode,
+        msg: &str,
+        creds: Option<Vec<u8>>,
+    ) -> LdapMsg {
+        LdapMsg {
+            msgid: self.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code,
+                    matcheddn: "".to_string(),
+                    message: msg.to_string(),
+                    referral: vec![],
+                },
+                saslcreds: creds,
+            }),
+            ctrl: vec![],
+        }
+    }
+
+    /// Continue a multi-step exchange, sending the server's challenge.
+    pub fn gen_in_progress(&self, creds: Option<Vec<u8>>) -> LdapMsg {
+        self.gen_response(LdapResultCode::SaslBindInProgress, "", creds)
+    }
+
+    /// Some mechanisms send additional data with the final success.
+    pub fn gen_success(&self, creds: Option<Vec<u8>>) -> LdapMsg {
+        self.gen_response(LdapResultCode::Success, "", creds)
+    }
+
+    pub fn gen_invalid_cred(&self) -> LdapMsg {
+        self.gen_response(LdapResultCode::InvalidCredentials, "", None)
+    }
+
+    pub fn gen_auth_method_not_supported(&self) -> LdapMsg {
+        self.gen_response(LdapResultCode::AuthMethodNotSupported, "", None)
+    }
+
+    pub fn gen_operror(&self, msg: &str) -> LdapMsg {
+        self.gen_response(LdapResultCode::OperationsError, msg, None)
+    }
+
+    pub fn gen_error(&self, rc: LdapResultCode, msg: String) -> LdapMsg {
+        self.gen_response(rc, msg.as_str(), None)
+    }
+
+    pub fn gen_referral(&self, urls: Vec<String>) -> LdapMsg {
+        let mut rmsg = self.gen_response(LdapResultCode::Referral, "", None);
+        if let LdapOp::BindResponse(lbr) = &mut rmsg.op {
+            lbr.res.referral = urls;
+        }
+        rmsg
+    }
 }
 
 impl WhoamiRequest {
@@ -243,4 +683,328 @@ impl WhoamiRequest {
             ctrl: vec![],
         }
     }
+
+    pub fn gen_referral(&self, urls: Vec<String>) -> LdapMsg {
+        LdapMsg {
+            msgid: self.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Referral,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: urls,
+                },
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::*;
+    use crate::simple::*;
+    use std::convert::TryFrom;
+
+    fn search_msg(ctrl: Vec<LdapControl>) -> LdapMsg {
+        LdapMsg {
+            msgid: 5,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::Present("objectClass".to_string()),
+                attrs: vec![],
+            }),
+            ctrl,
+        }
+    }
+
+    fn sasl_msg(mechanism: &str, credentials: Option<&[u8]>) -> LdapMsg {
+        LdapMsg {
+            msgid: 1,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
+                dn: "".to_string(),
+                cred: LdapBindCred::Sasl {
+                    mechanism: mechanism.to_string(),
+                    credentials: credentials.map(Vec::from),
+                },
+            }),
+            ctrl: vec![],
+        }
+    }
+
+    #[test]
+    fn test_simple_sasl_bind() {
+        match ServerOps::try_from(sasl_msg("EXTERNAL", None)) {
+            Ok(ServerOps::SaslBind(SaslBindRequest {
+                creds: SaslCredentials::External { authzid: None },
+                ..
+            })) => {}
+            _ => panic!("EXTERNAL not parsed"),
+        }
+
+        match ServerOps::try_from(sasl_msg("EXTERNAL", Some(b"dn:cn=demo"))) {
+            Ok(ServerOps::SaslBind(SaslBindRequest {
+                creds: SaslCredentials::External { authzid: Some(a) },
+                ..
+            })) => assert!(a == "dn:cn=demo"),
+            _ => panic!("EXTERNAL not parsed"),
+        }
+
+        match ServerOps::try_from(sasl_msg("PLAIN", Some(b"\0demo\0password"))) {
+            Ok(ServerOps::SaslBind(SaslBindRequest {
+                creds:
+                    SaslCredentials::Plain {
+                        authzid: None,
+                        authcid,
+                        password,
+                    },
+                ..
+            })) => assert!(authcid == "demo" && password == "password"),
+            _ => panic!("PLAIN not parsed"),
+        }
+
+        match ServerOps::try_from(sasl_msg("PLAIN", Some(b"admin\0demo\0password"))) {
+            Ok(ServerOps::SaslBind(SaslBindRequest {
+                creds:
+                    SaslCredentials::Plain {
+                        authzid: Some(authzid),
+                        ..
+                    },
+                ..
+            })) => assert!(authzid == "admin"),
+            _ => panic!("PLAIN not parsed"),
+        }
+
+        // Malformed PLAIN messages are answered with invalidCredentials,
+        // rather than failing the whole operation.
+        let malformed: [Option<&[u8]>; 5] = [
+            None,
+            Some(b"demo\0password"),
+            Some(b"\0demo\0"),
+            Some(b"\0a\0b\0c"),
+            Some(b"\0demo\0\xff\xfe"),
+        ];
+        for credentials in malformed.iter() {
+            match ServerOps::try_from(sasl_msg("PLAIN", *credentials)) {
+                Ok(ServerOps::InvalidSaslCredentials(LdapMsg {
+                    msgid: 1,
+                    op: LdapOp::BindResponse(LdapBindResponse { res, .. }),
+                    ..
+                })) => assert!(res.code == LdapResultCode::InvalidCredentials),
+                _ => panic!("malformed PLAIN not answered"),
+            }
+        }
+
+        // Anything else is passed through.
+        assert!(matches!(
+            ServerOps::try_from(sasl_msg("GSSAPI", Some(b"\x60\x82"))),
+            Ok(ServerOps::SaslBind(SaslBindRequest {
+                creds: SaslCredentials::Other { .. },
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_simple_bind_version() {
+        let bind = |version| LdapMsg {
+            msgid: 1,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                version,
+                dn: "cn=demo".to_string(),
+                cred: LdapBindCred::Simple("password".to_string()),
+            }),
+            ctrl: vec![],
+        };
+
+        match ServerOps::try_from(bind(3)) {
+            Ok(ServerOps::SimpleBind(sbr)) => assert_eq!(sbr.version, 3),
+            _ => panic!("v3 bind not accepted"),
+        }
+
+        match ServerOps::try_from(bind(2)) {
+            Ok(ServerOps::UnsupportedVersion(LdapMsg {
+                msgid: 1,
+                op: LdapOp::BindResponse(LdapBindResponse { res, .. }),
+                ..
+            })) => assert!(res.code == LdapResultCode::ProtocolError),
+            _ => panic!("v2 bind not refused"),
+        }
+    }
+
+    #[test]
+    fn test_simple_paged_search() {
+        const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+        let entries: Vec<_> = (0..5)
+            .map(|i| LdapSearchResultEntry {
+                dn: format!("cn={},dc=example,dc=com", i),
+                attributes: vec![],
+            })
+            .collect();
+        let search_with = |base: &str, size, cookie: &[u8]| {
+            let ctrl = LdapPagedResults {
+                size,
+                cookie: cookie.to_vec(),
+            };
+            match ServerOps::try_from(search_msg(vec![ctrl.into()])) {
+                Ok(ServerOps::Search(mut sr)) => {
+                    sr.base = base.to_string();
+                    sr.gen_paged(KEY, entries.clone())
+                }
+                _ => panic!("search not parsed"),
+            }
+        };
+        let search = |size, cookie: &[u8]| search_with("dc=example,dc=com", size, cookie);
+        let refused = |msgs: Vec<LdapMsg>| match msgs.as_slice() {
+            [LdapMsg {
+                op: LdapOp::SearchResultDone(res),
+                ..
+            }] => assert!(res.code == LdapResultCode::UnwillingToPerform),
+            msgs => panic!("unexpected response {:?}", msgs),
+        };
+        // The entries and the response control of a page.
+        let page = |mut msgs: Vec<LdapMsg>| {
+            let done = msgs.pop().expect("no result");
+            assert!(matches!(
+                done.op,
+                LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Success,
+                    ..
+                })
+            ));
+            let paged = LdapPagedResults::try_from(&done.ctrl[0]).expect("invalid control");
+            assert_eq!(paged.size, 5);
+            let dns: Vec<_> = msgs
+                .into_iter()
+                .map(|m| match m.op {
+                    LdapOp::SearchResultEntry(e) => e.dn,
+                    op => panic!("unexpected op {:?}", op),
+                })
+                .collect();
+            (dns, paged.cookie)
+        };
+
+        let (dns, cookie) = page(search(2, b""));
+        assert_eq!(
+            dns,
+            vec!["cn=0,dc=example,dc=com", "cn=1,dc=example,dc=com"]
+        );
+        let (dns, cookie) = page(search(2, &cookie));
+        assert_eq!(
+            dns,
+            vec!["cn=2,dc=example,dc=com", "cn=3,dc=example,dc=com"]
+        );
+        let (dns, last) = page(search(2, &cookie));
+        assert_eq!(dns, vec!["cn=4,dc=example,dc=com"]);
+        assert!(last.is_empty());
+
+        // A size of 0 abandons the search.
+        let (dns, last) = page(search(0, &cookie));
+        assert!(dns.is_empty() && last.is_empty());
+
+        // Cookies we didn't issue are refused.
+        refused(search(2, b"bogus"));
+        refused(search(2, &2u64.to_be_bytes()));
+
+        // As are cookies with a forged offset, or from another search.
+        let mut forged = cookie.clone();
+        forged[7] = 0;
+        refused(search(2, &forged));
+        refused(search_with("dc=example,dc=org", 2, &cookie));
+
+        // And cookies issued under another key.
+        match ServerOps::try_from(search_msg(vec![LdapPagedResults {
+            size: 2,
+            cookie: cookie.clone(),
+        }
+        .into()]))
+        {
+            Ok(ServerOps::Search(sr)) => refused(sr.gen_paged(b"another key", entries.clone())),
+            _ => panic!("search not parsed"),
+        }
+
+        // Without the control everything is returned at once.
+        match ServerOps::try_from(search_msg(vec![])) {
+            Ok(ServerOps::Search(sr)) => {
+                let msgs = sr.gen_paged(KEY, entries.clone());
+                assert_eq!(msgs.len(), 6);
+                assert!(msgs[5].ctrl.is_empty());
+            }
+            _ => panic!("search not parsed"),
+        }
+    }
+
+    #[test]
+    fn test_simple_critical_controls() {
+        let ctrl = vec![LdapControl {
+            oid: "1.2.840.113556.1.4.319".to_string(),
+            criticality: true,
+            value: None,
+        }];
+
+        // Not supported, so we get an error with the right msgid.
+        match ServerOps::try_from(search_msg(ctrl.clone())) {
+            Ok(ServerOps::UnavailableCriticalExtension(LdapMsg {
+                msgid: 5,
+                op: LdapOp::SearchResultDone(res),
+                ..
+            })) => assert!(res.code == LdapResultCode::UnavailableCriticalExtension),
+            _ => panic!("critical control not rejected"),
+        }
+
+        // Once registered it's passed through.
+        let mut controls = SupportedControls::new();
+        controls.register("1.2.840.113556.1.4.319");
+        assert!(matches!(
+            ServerOps::try_from_with_controls(search_msg(ctrl.clone()), &controls),
+            Ok(ServerOps::Search(_))
+        ));
+
+        // Unbind and abandon have no response, but must still not be done.
+        let unbind = LdapMsg {
+            msgid: 6,
+            op: LdapOp::UnbindRequest,
+            ctrl: ctrl.clone(),
+        };
+        assert!(matches!(
+            ServerOps::try_from(unbind),
+            Ok(ServerOps::DroppedCriticalExtension(LdapMsg {
+                msgid: 6,
+                ..
+            }))
+        ));
+        let abandon = LdapMsg {
+            msgid: 7,
+            op: LdapOp::AbandonRequest(5),
+            ctrl,
+        };
+        let controls = SupportedControls::new();
+        assert!(controls.check(&abandon).is_none());
+        assert!(matches!(
+            ServerOps::try_from_with_controls(abandon, &controls),
+            Ok(ServerOps::DroppedCriticalExtension(LdapMsg {
+                msgid: 7,
+                ..
+            }))
+        ));
+
+        // Non-critical controls are always ignored.
+        let ctrl = vec![LdapControl {
+            oid: "1.3.6.1.1.13.1".to_string(),
+            criticality: false,
+            value: None,
+        }];
+        assert!(matches!(
+            ServerOps::try_from(search_msg(ctrl)),
+            Ok(ServerOps::Search(_))
+        ));
+    }
 }